@@ -0,0 +1,129 @@
+/*
+ * C interface to rustic-onika, built as librustic_onika by `cargo build --release`.
+ *
+ * Every function but the free functions and the getters returns an
+ * onika_status, results come back through out pointers. On failure the out
+ * pointer is set to NULL and onika_last_error() describes the problem.
+ *
+ * Ownership:
+ * - onika_index, onika_sketch and onika_result objects are created by the
+ *   library and owned by the caller, who gives each of them back to its
+ *   onika_*_free function exactly once. The free functions accept NULL.
+ * - Strings and buffers passed to the library are only borrowed for the
+ *   duration of the call. Strings are NUL terminated UTF-8.
+ * - Pointers returned by the library (onika_last_error, onika_result_hits)
+ *   belong to the library, see each function for how long they stay valid.
+ *
+ * An index can be queried from several threads at once, but must not be
+ * modified (onika_index_insert) while it is used by another thread.
+ * No Rust panic crosses this interface, it is reported as ONIKA_PANIC.
+ */
+
+#ifndef RUSTIC_ONIKA_H
+#define RUSTIC_ONIKA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+    ONIKA_OK = 0,
+    ONIKA_IO = 1,                 /* a file cannot be read or written, or is corrupt */
+    ONIKA_INVALID_PARAMETERS = 2, /* invalid parameter or argument, NULL included */
+    ONIKA_INDEX_FULL = 3,         /* no genome id left in the id width */
+    ONIKA_INVALID_SEQUENCE = 4,   /* sequence that cannot be sketched */
+    ONIKA_SKETCH_SIZE = 5,        /* sketch made with other parameters than the index */
+    ONIKA_REJECTED_INPUT = 6,     /* input refused by a strict build, not returned by these functions yet */
+    ONIKA_PANIC = 7               /* internal error */
+} onika_status;
+
+/* Sketching parameters */
+typedef struct {
+    uint32_t k;  /* k-mer size, from 1 to 31 */
+    uint32_t lf; /* log2 of the number of bins of a sketch */
+    uint32_t w;  /* fingerprint size in bits */
+    uint32_t e;  /* expected genome size */
+} onika_params;
+
+/* A genome sharing `count` sketch positions with a query */
+typedef struct {
+    size_t gid;
+    uint32_t count;
+} onika_hit;
+
+typedef struct OnikaIndex onika_index;
+typedef struct OnikaSketch onika_sketch;
+typedef struct OnikaResult onika_result;
+
+/* Message of the last failure on the calling thread, NULL if none. Valid
+ * until the next failing call on the same thread. */
+const char *onika_last_error(void);
+
+/* Default sketching parameters, the ones of the command line */
+onika_status onika_params_default(onika_params *out);
+
+/* Empty index. `params` may be NULL for the defaults. Genome ids are 16, 32
+ * or 64 bits wide, sketch positions 16 or 32 bits (the command line uses 32
+ * and 16). */
+onika_status onika_index_new(const onika_params *params, uint32_t id_bits, uint32_t pos_bits,
+                             onika_index **out);
+
+/* Load an index written by onika_index_dump or by the command line */
+onika_status onika_index_load(const char *path, onika_index **out);
+
+onika_status onika_index_dump(const onika_index *index, const char *path);
+
+void onika_index_free(onika_index *index);
+
+onika_status onika_index_params(const onika_index *index, onika_params *out);
+
+/* Number of genomes, genome ids go from 0 to this number excluded */
+size_t onika_index_nb_genomes(const onika_index *index);
+
+/* Copy the name of a genome like snprintf: at most `buffer_len` bytes are
+ * written, NUL included. The length of the whole name goes to `name_len`
+ * when it is not NULL, so a call with a NULL buffer and a length of 0 gives
+ * the size to allocate. */
+onika_status onika_index_genome_name(const onika_index *index, size_t gid, char *buffer,
+                                     size_t buffer_len, size_t *name_len);
+
+/* Add a sketched genome, named `name` if not NULL (by default the path of
+ * a sketched file). Its genome id goes to `gid` when it is not NULL. The
+ * sketch is only read and still belongs to the caller. */
+onika_status onika_index_insert(onika_index *index, const onika_sketch *sketch, const char *name,
+                                size_t *gid);
+
+/* Fewest sketch positions a genome must share with a query to be reported
+ * (1 by default). It is saved by onika_index_dump. */
+onika_status onika_index_set_min_score(onika_index *index, uint32_t min_score);
+
+/* The `top` genomes (all of them if `top` is 0) sharing at least the
+ * minimum score of sketch positions with `sketch`, best first */
+onika_status onika_index_query(const onika_index *index, const onika_sketch *sketch, size_t top,
+                               onika_result **out);
+
+/* Sketch a FASTA or FASTQ file with the parameters of `index` */
+onika_status onika_sketch_file(const onika_index *index, const char *path, onika_sketch **out);
+
+/* Sketch `len` bytes of FASTA text, or of a bare sequence, with the
+ * parameters of `index`. `name` may be NULL. */
+onika_status onika_sketch_buffer(const onika_index *index, const char *name, const char *data,
+                                 size_t len, onika_sketch **out);
+
+void onika_sketch_free(onika_sketch *sketch);
+
+/* Hits of a query by decreasing count then increasing genome id, their
+ * number goes to `len`. The array belongs to `result` and stays valid until
+ * onika_result_free, it is NULL when there is no hit. */
+const onika_hit *onika_result_hits(const onika_result *result, size_t *len);
+
+void onika_result_free(onika_result *result);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
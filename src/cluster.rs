@@ -0,0 +1,209 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::index::Gid;
+use crate::metadata::GenomeMetadata;
+
+/// How genomes closer than the threshold are grouped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClusterMethod {
+    Single, // connected components of the neighbour graph
+    Greedy, // best genomes first, each one takes its neighbours not yet clustered
+}
+
+impl FromStr for ClusterMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ClusterMethod, String> {
+        match s {
+            "single" => Ok(ClusterMethod::Single),
+            "greedy" => Ok(ClusterMethod::Greedy),
+            _ => Err(format!("unknown cluster method '{}', expected single or greedy", s)),
+        }
+    }
+}
+
+impl fmt::Display for ClusterMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ClusterMethod::Single => "single",
+            ClusterMethod::Greedy => "greedy",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Clusters numbered from 0, by decreasing score of their representative
+pub struct Clustering {
+    pub cluster_of: Vec<usize>,     // cluster of each genome, by Gid
+    pub representatives: Vec<Gid>, // representative of each cluster
+}
+
+impl Clustering {
+    /// Group genomes by `neighbors`, the genomes close enough to each one
+    /// (both ways). The genome of highest score represents its cluster, ties
+    /// go to the smaller Gid. With the greedy method, every genome is a
+    /// neighbour of its representative.
+    pub fn build(method: ClusterMethod, neighbors: &[Vec<Gid>], scores: &[f64]) -> Clustering {
+        let size = neighbors.len();
+        let mut order: Vec<Gid> = (0..size).collect();
+        order.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]).then(a.cmp(&b)));
+
+        let mut components = UnionFind::new(size);
+        if method == ClusterMethod::Single {
+            for (gid, genome_neighbors) in neighbors.iter().enumerate() {
+                for &neighbor in genome_neighbors {
+                    components.union(gid, neighbor);
+                }
+            }
+        }
+
+        const NONE: usize = usize::MAX;
+        let mut cluster_of = vec![NONE; size];
+        let mut cluster_of_component = vec![NONE; size];
+        let mut representatives = Vec::new();
+        for gid in order {
+            match method {
+                ClusterMethod::Single => {
+                    let component = components.find(gid);
+                    if cluster_of_component[component] == NONE {
+                        cluster_of_component[component] = representatives.len();
+                        representatives.push(gid);
+                    }
+                    cluster_of[gid] = cluster_of_component[component];
+                }
+                ClusterMethod::Greedy => {
+                    if cluster_of[gid] != NONE {
+                        continue;
+                    }
+                    let cluster = representatives.len();
+                    representatives.push(gid);
+                    cluster_of[gid] = cluster;
+                    for &neighbor in &neighbors[gid] {
+                        if cluster_of[neighbor] == NONE {
+                            cluster_of[neighbor] = cluster;
+                        }
+                    }
+                }
+            }
+        }
+
+        Clustering {
+            cluster_of,
+            representatives,
+        }
+    }
+}
+
+/// Score of each genome for the choice of representatives: the numeric
+/// value of its `quality_tag`, or its number of bases without a tag name.
+/// Genomes without a numeric value for the tag come last.
+pub fn scores(genomes: &[GenomeMetadata], quality_tag: Option<&str>) -> Vec<f64> {
+    genomes
+        .iter()
+        .map(|metadata| match quality_tag {
+            Some(tag) => metadata
+                .tags
+                .iter()
+                .find(|(key, _)| key == tag)
+                .and_then(|(_, value)| value.parse().ok())
+                .unwrap_or(f64::NEG_INFINITY),
+            None => metadata.total_bases as f64,
+        })
+        .collect()
+}
+
+// Disjoint sets with path halving
+struct UnionFind {
+    parents: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(size: usize) -> UnionFind {
+        UnionFind {
+            parents: (0..size).collect(),
+        }
+    }
+
+    fn find(&mut self, mut x: usize) -> usize {
+        while self.parents[x] != x {
+            self.parents[x] = self.parents[self.parents[x]];
+            x = self.parents[x];
+        }
+        x
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Path 0 - 1 - 2 - 3, and 4 alone
+    fn path() -> Vec<Vec<Gid>> {
+        vec![vec![1], vec![0, 2], vec![1, 3], vec![2], vec![]]
+    }
+
+    fn genome(bases: usize, tags: &[(&str, &str)]) -> GenomeMetadata {
+        let mut metadata = GenomeMetadata::new("g", &["A".repeat(bases)], 0);
+        metadata.add_tags(tags.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect());
+        metadata
+    }
+
+    #[test]
+    fn single_linkage_merges_transitively() {
+        let clustering = Clustering::build(ClusterMethod::Single, &path(), &[1.0, 2.0, 5.0, 3.0, 4.0]);
+        assert_eq!(clustering.representatives, [2, 4]);
+        assert_eq!(clustering.cluster_of, [0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn greedy_only_takes_neighbours_of_the_representative() {
+        let clustering = Clustering::build(ClusterMethod::Greedy, &path(), &[1.0, 2.0, 5.0, 3.0, 4.0]);
+        // 2 takes 1 and 3, then 4 is alone and 0 is left to itself
+        assert_eq!(clustering.representatives, [2, 4, 0]);
+        assert_eq!(clustering.cluster_of, [2, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn ties_go_to_the_smaller_gid() {
+        let clustering = Clustering::build(ClusterMethod::Greedy, &path(), &[1.0; 5]);
+        assert_eq!(clustering.representatives, [0, 2, 4]);
+        assert_eq!(clustering.cluster_of, [0, 0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn representatives_are_the_largest_genomes_by_default() {
+        let genomes: Vec<GenomeMetadata> = [10, 30, 20, 5, 1].iter().map(|&bases| genome(bases, &[])).collect();
+        let by_size = scores(&genomes, None);
+        assert_eq!(by_size, [10.0, 30.0, 20.0, 5.0, 1.0]);
+        let clustering = Clustering::build(ClusterMethod::Single, &path(), &by_size);
+        assert_eq!(clustering.representatives, [1, 4]);
+    }
+
+    #[test]
+    fn representatives_follow_the_quality_tag() {
+        let genomes = [
+            genome(100, &[("completeness", "90.5")]),
+            genome(10, &[("completeness", "99")]),
+            genome(1000, &[("completeness", "high")]),
+            genome(1000, &[("contamination", "1")]),
+            genome(1, &[("completeness", "-3")]),
+        ];
+        let by_tag = scores(&genomes, Some("completeness"));
+        assert_eq!(by_tag, [90.5, 99.0, f64::NEG_INFINITY, f64::NEG_INFINITY, -3.0]);
+        let clustering = Clustering::build(ClusterMethod::Single, &path(), &by_tag);
+        assert_eq!(clustering.representatives, [1, 4]);
+
+        // Without any numeric value, the smaller Gid wins
+        let untagged = scores(&genomes[2..4], Some("completeness"));
+        let clustering = Clustering::build(ClusterMethod::Single, &[vec![1], vec![0]], &untagged);
+        assert_eq!(clustering.representatives, [0]);
+    }
+}
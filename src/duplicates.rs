@@ -0,0 +1,226 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::index::Gid;
+use crate::metadata::GenomeMetadata;
+
+/// What to do with a genome already present in the index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    Skip,       // leave it out of the index
+    Warn,       // index it again under a new id, with a warning
+    Alias,      // record its path as another path of the existing genome
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DuplicatePolicy, String> {
+        match s {
+            "skip" => Ok(DuplicatePolicy::Skip),
+            "warn" => Ok(DuplicatePolicy::Warn),
+            "alias" => Ok(DuplicatePolicy::Alias),
+            _ => Err(format!("unknown duplicate policy '{}', expected skip, warn or alias", s)),
+        }
+    }
+}
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DuplicatePolicy::Skip => "skip",
+            DuplicatePolicy::Warn => "warn",
+            DuplicatePolicy::Alias => "alias",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKind {
+    Path,       // same file, once symbolic links and relative paths are resolved
+    Sketch,     // another file with exactly the same sketch
+}
+
+impl DuplicateKind {
+    pub fn description(&self) -> &'static str {
+        match self {
+            DuplicateKind::Path => "is the same file as",
+            DuplicateKind::Sketch => "has the same sketch as",
+        }
+    }
+}
+
+pub struct Duplicate {
+    pub path: String,
+    pub original: Gid,
+    pub original_name: String,
+    pub kind: DuplicateKind,
+}
+
+/// Genomes seen so far by canonical path and by sketch. Sketches are looked
+/// up by a 64-bit hash, the caller compares the stored sketches of the
+/// candidates since distinct sketches can share a hash.
+pub struct DuplicateFinder {
+    policy: DuplicatePolicy,
+    paths: HashMap<PathBuf, Gid>,
+    sketches: HashMap<u64, Vec<Gid>>,
+    found: Vec<Duplicate>,
+}
+
+impl DuplicateFinder {
+    pub fn new(policy: DuplicatePolicy) -> DuplicateFinder {
+        DuplicateFinder {
+            policy,
+            paths: HashMap::new(),
+            sketches: HashMap::new(),
+            found: Vec::new(),
+        }
+    }
+
+    fn canonical(path: &str) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+    }
+
+    // `sketch` holds every bin of the genome, u64::MAX for an empty genome.
+    // Empty sketches are all identical and are never reported.
+    fn sketch_key(sketch: &[u64]) -> Option<u64> {
+        if sketch.iter().all(|&val| val == u64::MAX) {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        sketch.hash(&mut hasher);
+        Some(hasher.finish())
+    }
+
+    /// Register a genome of the index
+    pub fn add(&mut self, gid: Gid, paths: &[String], sketch: &[u64]) {
+        for path in paths {
+            self.paths.entry(DuplicateFinder::canonical(path)).or_insert(gid);
+        }
+        if let Some(key) = DuplicateFinder::sketch_key(sketch) {
+            self.sketches.entry(key).or_default().push(gid);
+        }
+    }
+
+    pub fn find_path(&self, path: &str) -> Option<Gid> {
+        self.paths.get(&DuplicateFinder::canonical(path)).copied()
+    }
+
+    /// Genomes whose sketch hashes like `sketch`, by Gid
+    pub fn sketch_candidates(&self, sketch: &[u64]) -> &[Gid] {
+        DuplicateFinder::sketch_key(sketch)
+            .and_then(|key| self.sketches.get(&key))
+            .map_or(&[], |gids| gids.as_slice())
+    }
+
+    pub fn policy(&self) -> DuplicatePolicy {
+        self.policy
+    }
+
+    /// The duplicate recorded by the last call to handle
+    pub fn last(&self) -> Option<&Duplicate> {
+        self.found.last()
+    }
+
+    /// Record a duplicate of the genome described by `original_metadata` and
+    /// apply the policy, returns whether the duplicate is left out of the index
+    pub fn handle(&mut self, path: &str, original: Gid, original_metadata: &mut GenomeMetadata, kind: DuplicateKind) -> bool {
+        self.found.push(Duplicate {
+            path: path.to_string(),
+            original,
+            original_name: original_metadata.name.clone(),
+            kind,
+        });
+        match self.policy {
+            DuplicatePolicy::Skip => true,
+            DuplicatePolicy::Warn => false,
+            DuplicatePolicy::Alias => {
+                if !original_metadata.paths.iter().any(|known| known == path) {
+                    original_metadata.paths.push(path.to_string());
+                }
+                true
+            }
+        }
+    }
+
+    /// Duplicates met so far, in input order
+    pub fn found(&self) -> &[Duplicate] {
+        &self.found
+    }
+}
+
+/// Count of duplicates by kind, then one tab separated line per duplicate
+impl fmt::Display for DuplicateFinder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let by_path = self.found.iter().filter(|dup| dup.kind == DuplicateKind::Path).count();
+        write!(
+            f,
+            "{} duplicate genomes ({} by path, {} by sketch), policy: {}",
+            self.found.len(),
+            by_path,
+            self.found.len() - by_path,
+            self.policy
+        )?;
+        write!(f, "\nduplicate\toriginal id\toriginal name\tmatched by")?;
+        for dup in &self.found {
+            let kind = match dup.kind {
+                DuplicateKind::Path => "path",
+                DuplicateKind::Sketch => "sketch",
+            };
+            write!(f, "\n{}\t{}\t{}\t{}", dup.path, dup.original, dup.original_name, kind)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias_records_each_path_once() {
+        let mut finder = DuplicateFinder::new(DuplicatePolicy::Alias);
+        let mut original = GenomeMetadata::new("a.fasta", &["ACGT".to_string()], 1);
+        assert!(finder.handle("a.fasta", 0, &mut original, DuplicateKind::Path));
+        assert!(finder.handle("b.fasta", 0, &mut original, DuplicateKind::Sketch));
+        assert!(finder.handle("b.fasta", 0, &mut original, DuplicateKind::Path));
+        assert_eq!(original.paths, ["a.fasta", "b.fasta"]);
+        assert_eq!(finder.found.len(), 3);
+    }
+
+    #[test]
+    fn every_genome_of_a_sketch_is_a_candidate() {
+        let mut finder = DuplicateFinder::new(DuplicatePolicy::Skip);
+        let (a, b) = (vec![1, 2, 3], vec![3, 2, 1]);
+        finder.add(0, &[], &a);
+        finder.add(1, &[], &b);
+        finder.add(2, &[], &a);
+        finder.add(3, &[], &[u64::MAX; 3]);
+        assert_eq!(finder.sketch_candidates(&a), [0, 2]);
+        assert_eq!(finder.sketch_candidates(&b), [1]);
+        assert!(finder.sketch_candidates(&[4, 5, 6]).is_empty());
+        assert!(finder.sketch_candidates(&[u64::MAX; 3]).is_empty());
+    }
+
+    #[test]
+    fn summary_lists_every_duplicate() {
+        let mut finder = DuplicateFinder::new(DuplicatePolicy::Warn);
+        let mut original = GenomeMetadata::new("a.fasta", &[], 1);
+        finder.handle("a.fasta", 0, &mut original, DuplicateKind::Path);
+        finder.handle("b.fasta", 0, &mut original, DuplicateKind::Sketch);
+        assert_eq!(finder.found().len(), 2);
+        assert_eq!(
+            finder.to_string(),
+            "2 duplicate genomes (1 by path, 1 by sketch), policy: warn\n\
+             duplicate\toriginal id\toriginal name\tmatched by\n\
+             a.fasta\t0\ta.fasta\tpath\n\
+             b.fasta\t0\ta.fasta\tsketch"
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+use std::io;
+
+/// Errors returned by the index, the builders and the queries
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),                                  // reading or writing a file, including corrupt index files
+    InvalidParameters(String),                      // parameters the index cannot represent
+    IndexFull { id_bits: u32, max_genomes: usize }, // no genome id left in the id width
+    InvalidSequence(String),                        // sequence that cannot be sketched
+    SketchSize { expected: usize, found: usize },   // sketch made with another sketch size
+    RejectedInput(String),                          // input refused by a strict build
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidParameters(msg) => write!(f, "invalid parameters: {}", msg),
+            Error::IndexFull { id_bits, max_genomes } => {
+                write!(f, "the index is full, {}-bit genome ids hold at most {} genomes", id_bits, max_genomes)
+            }
+            Error::InvalidSequence(msg) => write!(f, "invalid sequence: {}", msg),
+            Error::SketchSize { expected, found } => {
+                write!(f, "sketch of {} bins for an index of {} bins", found, expected)
+            }
+            Error::RejectedInput(msg) => write!(f, "rejected input, {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
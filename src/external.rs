@@ -0,0 +1,504 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use rayon::prelude::*;
+
+use crate::error::{Error, Result};
+use crate::duplicates::{DuplicateFinder, DuplicateKind};
+use crate::index::{Gid, Index};
+use crate::inputs::{BuildEvent, InputIssue, InputReport};
+use crate::metadata::{self, GenomeMetadata};
+use crate::sketch_store::SketchStore;
+use crate::sketcher::Sketch;
+use crate::storage::{self, Checksummed, Header};
+use crate::width::IndexInt;
+
+// Maximal number of runs opened at once by a merge pass
+const MAX_FAN_IN: usize = 128;
+
+// Builders of a process number their temporary files apart
+static NEXT_BUILDER: AtomicUsize = AtomicUsize::new(0);
+
+/// Field order gives the sort order of the runs, which is the order in which
+/// Index::insert_sketch fills the buckets of an in-memory index
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Posting<G, P> {
+    fingerprint: u32,
+    gid: G,
+    position: P,
+}
+
+// A posting spilled to a run: fingerprint (u32), position (P), gid (G)
+fn write_posting<W: Write, G: IndexInt, P: IndexInt>(writer: &mut W, posting: &Posting<G, P>) -> io::Result<()> {
+    storage::write_u32(writer, posting.fingerprint)?;
+    posting.position.write(writer)?;
+    posting.gid.write(writer)
+}
+
+fn read_posting<R: Read, G: IndexInt, P: IndexInt>(reader: &mut R) -> io::Result<Option<Posting<G, P>>> {
+    // Runs always end on a whole posting, an end of file on the first field
+    // is the end of the run
+    let fingerprint = match storage::read_u32(reader) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let position = P::read(reader)?;
+    let gid = G::read(reader)?;
+    Ok(Some(Posting {
+        fingerprint,
+        gid,
+        position,
+    }))
+}
+
+// K-way merge of sorted runs, postings are handed to `emit` in sorted order
+fn merge_runs<G: IndexInt, P: IndexInt, F>(runs: &[PathBuf], mut emit: F) -> io::Result<()>
+where
+    F: FnMut(Posting<G, P>) -> io::Result<()>,
+{
+    let mut readers = Vec::with_capacity(runs.len());
+    for run in runs {
+        readers.push(BufReader::new(File::open(run)?));
+    }
+
+    let mut heap = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(posting) = read_posting(reader)? {
+            heap.push(Reverse((posting, run)));
+        }
+    }
+    while let Some(Reverse((posting, run))) = heap.pop() {
+        emit(posting)?;
+        if let Some(next) = read_posting(&mut readers[run])? {
+            heap.push(Reverse((next, run)));
+        }
+    }
+    Ok(())
+}
+
+/// Out of core construction: sketches are turned into postings which are
+/// spilled to sorted runs whenever the memory budget is reached, then the
+/// runs are k-way merged directly into the persisted index format.
+/// The packed sketches are streamed to their own temporary file.
+pub struct ExternalBuilder<'a, G: IndexInt, P: IndexInt> {
+    index: &'a Index<G, P>,
+    max_postings: usize,
+    tmp_dir: PathBuf,
+    tmp_prefix: String, // onika-{pid}-{builder}, unique to this builder
+    postings: Vec<Posting<G, P>>,
+    runs: Vec<PathBuf>,
+    run_count: usize,
+    sketch_path: PathBuf,
+    sketch_run: Option<BufWriter<File>>,
+    empty_sketches: Vec<bool>,
+    metadata: Vec<GenomeMetadata>,
+    genome_numbers: usize,
+}
+
+impl<'a, G: IndexInt, P: IndexInt> ExternalBuilder<'a, G, P> {
+    /// `index` only provides the sketching parameters and the shard to
+    /// build, its buckets stay empty
+    pub fn new(index: &'a Index<G, P>, memory_budget: usize, tmp_dir: &Path) -> ExternalBuilder<'a, G, P> {
+        let max_postings = (memory_budget / std::mem::size_of::<Posting<G, P>>()).max(1);
+        let tmp_prefix = format!("onika-{}-{}", std::process::id(), NEXT_BUILDER.fetch_add(1, Ordering::Relaxed));
+        ExternalBuilder {
+            index,
+            max_postings,
+            tmp_dir: tmp_dir.to_path_buf(),
+            // allocated once, growing by doubling would overshoot the budget
+            postings: Vec::with_capacity(max_postings),
+            runs: Vec::new(),
+            run_count: 0,
+            sketch_path: tmp_dir.join(format!("{}-sketches.tmp", tmp_prefix)),
+            tmp_prefix,
+            sketch_run: None,
+            empty_sketches: Vec::new(),
+            metadata: Vec::new(),
+            genome_numbers: 0,
+        }
+    }
+
+    pub fn get_nb_genomes(&self) -> usize {
+        self.genome_numbers
+    }
+
+    /// Same input as Index::get_filename, files are sketched in parallel batches
+    pub fn get_filename(
+        &mut self,
+        filestr: &str,
+        duplicates: &mut DuplicateFinder,
+        inputs: &mut InputReport,
+        progress: &mut dyn FnMut(BuildEvent),
+    ) -> Result<()> {
+        if self.index.get_nb_genomes() > 0 {
+            return Err(Error::InvalidParameters(String::from(
+                "an out of core build starts from an empty index, it cannot extend a loaded one",
+            )));
+        }
+        let reader = BufReader::new(File::open(filestr)?);
+        let mut genomes = Vec::new();
+        for (line_number, line) in reader.lines().enumerate() {
+            let (filename, tags, ignored) = metadata::parse_fof_line(&line?);
+            for field in &ignored {
+                progress(BuildEvent::IgnoredTag(&filename, field));
+            }
+            if !filename.is_empty() {
+                genomes.push((line_number + 1, filename, tags));
+            }
+        }
+
+        let batch_size = rayon::current_num_threads() * 4;
+        for batch in genomes.chunks(batch_size) {
+            let sketches: Vec<_> = batch
+                .par_iter()
+                .map(|(_, filename, _)| self.index.sketcher().sketch_input(filename))
+                .collect();
+            for ((line_number, filename, tags), sketch) in batch.iter().zip(sketches) {
+                let (sketch, mut metadata) = match sketch {
+                    Ok(genome) => genome,
+                    Err((kind, message)) => {
+                        let issue = inputs.add(InputIssue {
+                            line: *line_number,
+                            path: filename.clone(),
+                            kind,
+                            message,
+                        })?;
+                        progress(BuildEvent::Skipped(issue));
+                        continue;
+                    }
+                };
+                let mut duplicate = duplicates.find_path(filename).map(|original| (original, DuplicateKind::Path));
+                if duplicate.is_none() {
+                    for &candidate in duplicates.sketch_candidates(sketch.bins()) {
+                        if self.same_sketch(candidate, &sketch)? {
+                            duplicate = Some((candidate, DuplicateKind::Sketch));
+                            break;
+                        }
+                    }
+                }
+                if let Some((original, kind)) = duplicate {
+                    let original_metadata = self.metadata.get_mut(original).ok_or_else(|| {
+                        Error::InvalidParameters(format!("the duplicate finder knows genome {}, which is not in this build", original))
+                    })?;
+                    let skip = duplicates.handle(filename, original, original_metadata, kind);
+                    if let Some(found) = duplicates.last() {
+                        progress(BuildEvent::Duplicate(found, duplicates.policy()));
+                    }
+                    if skip {
+                        continue;
+                    }
+                }
+                progress(BuildEvent::Adding(filename));
+                metadata.add_tags(tags.clone());
+                let gid = self.insert_genome(&sketch, metadata)?;
+                duplicates.add(gid, std::slice::from_ref(filename), sketch.bins());
+                progress(BuildEvent::Added(filename, gid));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert_genome(&mut self, sketch: &Sketch, metadata: GenomeMetadata) -> Result<Gid> {
+        if self.genome_numbers >= self.index.get_max_genomes() {
+            return Err(Error::IndexFull {
+                id_bits: G::BYTES * 8,
+                max_genomes: self.index.get_max_genomes(),
+            });
+        }
+        let params = self.index.params();
+        if sketch.nb_bins() != params.sketch_size() {
+            return Err(Error::SketchSize {
+                expected: params.sketch_size(),
+                found: sketch.nb_bins(),
+            });
+        }
+        let gid = self.genome_numbers;
+        self.genome_numbers += 1;
+        let range = self.index.get_position_range();
+
+        if self.sketch_run.is_none() {
+            self.sketch_run = Some(BufWriter::new(File::create(&self.sketch_path)?));
+        }
+        let (packed, empty) = SketchStore::pack(params.w, &sketch.bins()[range.clone()]);
+        if let Some(sketch_run) = self.sketch_run.as_mut() {
+            for word in packed {
+                storage::write_u64(sketch_run, word)?;
+            }
+        }
+        self.empty_sketches.push(empty);
+        self.metadata.push(metadata);
+
+        for (i, &val) in sketch.bins().iter().enumerate() {
+            if range.contains(&i) && val < params.fingerprint_range() {
+                self.postings.push(Posting {
+                    fingerprint: val as u32,
+                    position: P::from_usize(i),
+                    gid: G::from_usize(gid),
+                });
+                if self.postings.len() >= self.max_postings {
+                    self.spill()?;
+                }
+            }
+        }
+        Ok(gid)
+    }
+
+    // Whether genome `gid` of this build has the sketch `sketch`, on the
+    // positions of the shard, read back from the temporary sketch file
+    fn same_sketch(&mut self, gid: Gid, sketch: &Sketch) -> io::Result<bool> {
+        let params = self.index.params();
+        let range = self.index.get_position_range();
+        let nb_words = SketchStore::words_per_genome(params.w, range.len());
+        if let Some(sketch_run) = self.sketch_run.as_mut() {
+            sketch_run.flush()?;
+        }
+        let mut reader = File::open(&self.sketch_path)?;
+        reader.seek(SeekFrom::Start((gid * nb_words * 8) as u64))?;
+        let mut words = Vec::with_capacity(nb_words);
+        for _ in 0..nb_words {
+            words.push(storage::read_u64(&mut reader)?);
+        }
+        let (packed, empty) = SketchStore::pack(params.w, &sketch.bins()[range]);
+        Ok(self.empty_sketches[gid] == empty && words == packed)
+    }
+
+    fn next_run_path(&mut self) -> PathBuf {
+        self.run_count += 1;
+        self.tmp_dir.join(format!("{}-run{}.tmp", self.tmp_prefix, self.run_count))
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if self.postings.is_empty() {
+            return Ok(());
+        }
+        self.postings.sort_unstable();
+        let path = self.next_run_path();
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for posting in &self.postings {
+            write_posting(&mut writer, posting)?;
+        }
+        writer.flush()?;
+        self.runs.push(path);
+        self.postings.clear();
+        Ok(())
+    }
+
+    // Merge runs together until they can all be opened by the final pass
+    fn reduce_runs(&mut self) -> io::Result<()> {
+        while self.runs.len() > MAX_FAN_IN {
+            let group: Vec<PathBuf> = self.runs.drain(..MAX_FAN_IN).collect();
+            let path = self.next_run_path();
+            // The new run is registered first so that it is cleaned up on failure
+            self.runs.push(path.clone());
+            let mut writer = BufWriter::new(File::create(&path)?);
+            let merged = merge_runs(&group, |posting: Posting<G, P>| write_posting(&mut writer, &posting));
+            for run in &group {
+                let _ = fs::remove_file(run);
+            }
+            merged?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Merge every run into the index file at `path`, which is removed if
+    /// the merge fails
+    pub fn finish(mut self, path: &Path) -> Result<()> {
+        let written = self.write_index(path);
+        if written.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        written
+    }
+
+    fn write_index(&mut self, path: &Path) -> Result<()> {
+        self.spill()?;
+        self.reduce_runs()?;
+
+        let header = Header {
+            genome_numbers: self.genome_numbers as u64,
+            ..self.index.header()
+        };
+        let fingerprint_range = header.fingerprint_range();
+        let mut writer = Checksummed::new(BufWriter::new(File::create(path)?));
+        storage::write_header(&mut writer, &header)?;
+        storage::write_checksum(&mut writer)?;
+
+        let mut fingerprint = 0u64;
+        let mut gids: Vec<G> = Vec::new();
+        let mut positions: Vec<P> = Vec::new();
+        merge_runs(&self.runs, |posting| {
+            while fingerprint < posting.fingerprint as u64 {
+                storage::write_bucket(&mut writer, &gids, &positions)?;
+                gids.clear();
+                positions.clear();
+                fingerprint += 1;
+            }
+            gids.push(posting.gid);
+            positions.push(posting.position);
+            Ok(())
+        })?;
+        while fingerprint < fingerprint_range {
+            storage::write_bucket(&mut writer, &gids, &positions)?;
+            gids.clear();
+            positions.clear();
+            fingerprint += 1;
+        }
+        storage::write_checksum(&mut writer)?;
+
+        for &empty in &self.empty_sketches {
+            writer.write_all(&[empty as u8])?;
+        }
+        if let Some(mut sketch_run) = self.sketch_run.take() {
+            sketch_run.flush()?;
+            drop(sketch_run);
+            io::copy(&mut File::open(&self.sketch_path)?, &mut writer)?;
+        }
+        storage::write_checksum(&mut writer)?;
+
+        for metadata in &self.metadata {
+            metadata.write(&mut writer)?;
+        }
+        storage::write_checksum(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<G: IndexInt, P: IndexInt> Drop for ExternalBuilder<'_, G, P> {
+    fn drop(&mut self) {
+        for run in &self.runs {
+            let _ = fs::remove_file(run);
+        }
+        let _ = fs::remove_file(&self.sketch_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketcher::Params;
+
+    const PARAMS: Params = Params { k: 15, lf: 8, w: 8, e: 1000 };
+
+    fn synthetic_sketches(seed: u64) -> Vec<Sketch> {
+        (0..3u64)
+            .map(|gid| {
+                let bins = (0..256u64)
+                    .map(|i| match (i + gid + seed) % 17 {
+                        0 => u64::MAX,
+                        _ => (i * 7 + gid * 31 + seed) % 256,
+                    })
+                    .collect();
+                Sketch::from_bins(bins)
+            })
+            .collect()
+    }
+
+    fn metadata(gid: usize) -> GenomeMetadata {
+        GenomeMetadata::new(&format!("g{}", gid), &[], 0)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("onika-external-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Bytes of the in-memory index of `sketches`, as written by dump
+    fn dumped(sketches: &[Sketch], path: &Path) -> Vec<u8> {
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        for (gid, sketch) in sketches.iter().enumerate() {
+            assert_eq!(index.insert_genome(sketch, metadata(gid)).unwrap(), gid);
+        }
+        index.dump(path).unwrap();
+        fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn finish_writes_the_bytes_of_dump() {
+        let index: Index = Index::new(PARAMS).unwrap();
+        let sketches = synthetic_sketches(0);
+        let tmp_dir = test_dir("dump");
+        // two postings per run: several hundred runs, more than one merge pass
+        let budget = 2 * std::mem::size_of::<Posting<u32, u16>>();
+        let mut builder = ExternalBuilder::new(&index, budget, &tmp_dir);
+        for (gid, sketch) in sketches.iter().enumerate() {
+            builder.insert_genome(sketch, metadata(gid)).unwrap();
+        }
+        let external = tmp_dir.join("external.idx");
+        builder.finish(&external).unwrap();
+
+        let same = fs::read(&external).unwrap() == dumped(&sketches, &tmp_dir.join("in_memory.idx"));
+        fs::remove_dir_all(&tmp_dir).unwrap();
+        assert!(same);
+    }
+
+    #[test]
+    fn builders_of_a_process_keep_their_runs_apart() {
+        let index: Index = Index::new(PARAMS).unwrap();
+        let tmp_dir = test_dir("apart");
+        let budget = 8 * std::mem::size_of::<Posting<u32, u16>>();
+        let mut first = ExternalBuilder::new(&index, budget, &tmp_dir);
+        let mut second = ExternalBuilder::new(&index, budget, &tmp_dir);
+        let (first_sketches, second_sketches) = (synthetic_sketches(0), synthetic_sketches(5));
+        for (gid, (a, b)) in first_sketches.iter().zip(&second_sketches).enumerate() {
+            first.insert_genome(a, metadata(gid)).unwrap();
+            second.insert_genome(b, metadata(gid)).unwrap();
+        }
+        first.finish(&tmp_dir.join("first.idx")).unwrap();
+        second.finish(&tmp_dir.join("second.idx")).unwrap();
+
+        let first_same = fs::read(tmp_dir.join("first.idx")).unwrap() == dumped(&first_sketches, &tmp_dir.join("a.idx"));
+        let second_same = fs::read(tmp_dir.join("second.idx")).unwrap() == dumped(&second_sketches, &tmp_dir.join("b.idx"));
+        fs::remove_dir_all(&tmp_dir).unwrap();
+        assert!(first_same && second_same);
+    }
+
+    #[test]
+    fn stored_sketches_are_compared_bin_by_bin() {
+        let index: Index = Index::new(PARAMS).unwrap();
+        let tmp_dir = test_dir("same");
+        let mut builder = ExternalBuilder::new(&index, 1 << 20, &tmp_dir);
+        let sketches = synthetic_sketches(0);
+        for (gid, sketch) in sketches.iter().enumerate() {
+            builder.insert_genome(sketch, metadata(gid)).unwrap();
+        }
+        let empty = Sketch::from_bins(vec![Sketch::EMPTY; 256]);
+        builder.insert_genome(&empty, metadata(3)).unwrap();
+        let same: Vec<Vec<bool>> = (0..4)
+            .map(|gid| {
+                sketches.iter().chain([&empty]).map(|sketch| builder.same_sketch(gid, sketch).unwrap()).collect()
+            })
+            .collect();
+        drop(builder);
+        fs::remove_dir_all(&tmp_dir).unwrap();
+        for (gid, row) in same.iter().enumerate() {
+            for (other, &same) in row.iter().enumerate() {
+                assert_eq!(same, gid == other, "genome {} against sketch {}", gid, other);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_finish_removes_the_output() {
+        let index: Index = Index::new(PARAMS).unwrap();
+        let tmp_dir = test_dir("failed");
+        let budget = 8 * std::mem::size_of::<Posting<u32, u16>>();
+        let mut builder = ExternalBuilder::new(&index, budget, &tmp_dir);
+        for (gid, sketch) in synthetic_sketches(0).iter().enumerate() {
+            builder.insert_genome(sketch, metadata(gid)).unwrap();
+        }
+        fs::remove_file(&builder.runs[0]).unwrap();
+        let output = tmp_dir.join("partial.idx");
+        let finished = builder.finish(&output);
+        let left = output.exists();
+        fs::remove_dir_all(&tmp_dir).unwrap();
+        assert!(finished.is_err());
+        assert!(!left);
+    }
+}
@@ -0,0 +1,668 @@
+//! C interface to the library, declared in include/rustic_onika.h.
+//!
+//! Every function returns an onika_status and hands its results back through
+//! out pointers. Objects created by the library (indexes, sketches, results)
+//! are owned by the caller until given back to their onika_*_free function.
+//! Strings passed in are borrowed for the duration of the call and must be
+//! NUL terminated UTF-8. Panics are caught before they reach the caller and
+//! reported as ONIKA_PANIC.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use crate::error::{Error, Result};
+use crate::index::{Gid, Index};
+use crate::metadata::GenomeMetadata;
+use crate::query::Hit;
+use crate::sketcher::{self, Params, Sketch, Sketcher};
+use crate::storage;
+use crate::width::{self, IndexInt, WidthVisitor};
+
+/// Outcome of a call, the message of a failure is given by onika_last_error
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnikaStatus {
+    Ok = 0,
+    Io = 1,
+    InvalidParameters = 2,
+    IndexFull = 3,
+    InvalidSequence = 4,
+    SketchSize = 5,
+    RejectedInput = 6,
+    Panic = 7,
+}
+
+impl From<&Error> for OnikaStatus {
+    fn from(e: &Error) -> OnikaStatus {
+        match e {
+            Error::Io(_) => OnikaStatus::Io,
+            Error::InvalidParameters(_) => OnikaStatus::InvalidParameters,
+            Error::IndexFull { .. } => OnikaStatus::IndexFull,
+            Error::InvalidSequence(_) => OnikaStatus::InvalidSequence,
+            Error::SketchSize { .. } => OnikaStatus::SketchSize,
+            Error::RejectedInput(_) => OnikaStatus::RejectedInput,
+        }
+    }
+}
+
+// What the C interface does with an index, whatever its widths
+trait AnyIndex: Send + Sync {
+    fn sketcher(&self) -> &Sketcher;
+
+    fn nb_genomes(&self) -> usize;
+
+    fn name(&self, gid: Gid) -> Option<&str>;
+
+    fn insert(&mut self, sketch: &Sketch, metadata: GenomeMetadata) -> Result<Gid>;
+
+    fn set_min_score(&mut self, min_score: u32);
+
+    fn query(&self, sketch: &Sketch, top: Option<usize>) -> Result<Vec<Hit>>;
+
+    fn dump(&self, path: &Path) -> Result<()>;
+}
+
+impl<G: IndexInt, P: IndexInt> AnyIndex for Index<G, P> {
+    fn sketcher(&self) -> &Sketcher {
+        Index::sketcher(self)
+    }
+
+    fn nb_genomes(&self) -> usize {
+        self.get_nb_genomes()
+    }
+
+    fn name(&self, gid: Gid) -> Option<&str> {
+        self.get_name(gid)
+    }
+
+    fn insert(&mut self, sketch: &Sketch, metadata: GenomeMetadata) -> Result<Gid> {
+        self.insert_genome(sketch, metadata)
+    }
+
+    fn set_min_score(&mut self, min_score: u32) {
+        Index::set_min_score(self, min_score)
+    }
+
+    fn query(&self, sketch: &Sketch, top: Option<usize>) -> Result<Vec<Hit>> {
+        Ok(self.query_sketch(sketch)?.ranked(self.get_min_score(), top))
+    }
+
+    fn dump(&self, path: &Path) -> Result<()> {
+        Index::dump(self, path)
+    }
+}
+
+// Empty index of the widths given to with_widths
+struct NewIndex(Params);
+
+impl WidthVisitor for NewIndex {
+    type Output = Result<Box<dyn AnyIndex>>;
+
+    fn visit<G: IndexInt, P: IndexInt>(self) -> Self::Output {
+        Ok(Box::new(Index::<G, P>::new(self.0)?))
+    }
+}
+
+// A loaded index keeps the widths it was built with
+struct LoadIndex<'a>(&'a Path);
+
+impl WidthVisitor for LoadIndex<'_> {
+    type Output = Result<Box<dyn AnyIndex>>;
+
+    fn visit<G: IndexInt, P: IndexInt>(self) -> Self::Output {
+        Ok(Box::new(Index::<G, P>::load(self.0)?))
+    }
+}
+
+/// onika_index
+pub struct OnikaIndex {
+    index: Box<dyn AnyIndex>,
+}
+
+/// onika_sketch: a sketch and the metadata of the genome it was made from
+pub struct OnikaSketch {
+    sketch: Sketch,
+    metadata: GenomeMetadata,
+}
+
+/// onika_result: the hits of a query, best first
+pub struct OnikaResult {
+    hits: Vec<Hit>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    // A message cannot hold a NUL byte, cut it there if it does
+    let message = match CString::new(message) {
+        Ok(message) => message,
+        Err(e) => {
+            let end = e.nul_position();
+            let mut bytes = e.into_vec();
+            bytes.truncate(end);
+            CString::new(bytes).unwrap_or_default()
+        }
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Run the body of an exported function, turning errors and panics into a status
+fn guard<F: FnOnce() -> Result<()>>(body: F) -> OnikaStatus {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => OnikaStatus::Ok,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            OnikaStatus::from(&e)
+        }
+        Err(_) => {
+            set_last_error(String::from("internal error, the call panicked"));
+            OnikaStatus::Panic
+        }
+    }
+}
+
+unsafe fn get_ref<'a, T>(ptr: *const T, what: &str) -> Result<&'a T> {
+    ptr.as_ref()
+        .ok_or_else(|| Error::InvalidParameters(format!("{} is NULL", what)))
+}
+
+unsafe fn get_mut<'a, T>(ptr: *mut T, what: &str) -> Result<&'a mut T> {
+    ptr.as_mut()
+        .ok_or_else(|| Error::InvalidParameters(format!("{} is NULL", what)))
+}
+
+unsafe fn get_str<'a>(ptr: *const c_char, what: &str) -> Result<&'a str> {
+    if ptr.is_null() {
+        return Err(Error::InvalidParameters(format!("{} is NULL", what)));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| Error::InvalidParameters(format!("{} is not UTF-8", what)))
+}
+
+// Out pointer for a new object, reset to NULL until the object exists
+unsafe fn get_out<'a, T>(out: *mut *mut T) -> Result<&'a mut *mut T> {
+    let out = get_mut(out, "out")?;
+    *out = ptr::null_mut();
+    Ok(out)
+}
+
+/// Message of the last failure on the calling thread, NULL if none. The
+/// string stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn onika_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |message| message.as_ptr()))
+}
+
+/// Default sketching parameters, the ones of the command line.
+///
+/// # Safety
+/// `out` must be NULL or point to writable memory for an onika_params.
+#[no_mangle]
+pub unsafe extern "C" fn onika_params_default(out: *mut Params) -> OnikaStatus {
+    guard(|| {
+        *get_mut(out, "out")? = Params::default();
+        Ok(())
+    })
+}
+
+/// Empty index with the given parameters (the defaults if `params` is NULL)
+/// and widths of genome ids and sketch positions, in bits.
+///
+/// # Safety
+/// `params` must be NULL or point to an onika_params. `out` must point to
+/// writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_new(
+    params: *const Params,
+    id_bits: u32,
+    pos_bits: u32,
+    out: *mut *mut OnikaIndex,
+) -> OnikaStatus {
+    guard(|| {
+        let out = get_out(out)?;
+        let params = params.as_ref().copied().unwrap_or_default();
+        let index = width::with_widths(id_bits, pos_bits, NewIndex(params)).map_err(Error::InvalidParameters)??;
+        *out = Box::into_raw(Box::new(OnikaIndex { index }));
+        Ok(())
+    })
+}
+
+/// Load an index written by onika_index_dump or by the command line.
+///
+/// # Safety
+/// `path` must be a NUL terminated string. `out` must point to writable
+/// memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_load(path: *const c_char, out: *mut *mut OnikaIndex) -> OnikaStatus {
+    guard(|| {
+        let out = get_out(out)?;
+        let path = Path::new(get_str(path, "path")?);
+        let header = storage::peek_header(path)?;
+        let index = width::with_widths(header.id_bytes * 8, header.pos_bytes * 8, LoadIndex(path))
+            .map_err(Error::InvalidParameters)??;
+        *out = Box::into_raw(Box::new(OnikaIndex { index }));
+        Ok(())
+    })
+}
+
+/// Write the index to `path`.
+///
+/// # Safety
+/// `index` must come from onika_index_new or onika_index_load and not be
+/// freed. `path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_dump(index: *const OnikaIndex, path: *const c_char) -> OnikaStatus {
+    guard(|| {
+        let path = Path::new(get_str(path, "path")?);
+        get_ref(index, "index")?.index.dump(path)
+    })
+}
+
+/// Free an index, NULL is ignored.
+///
+/// # Safety
+/// `index` must be NULL or come from onika_index_new or onika_index_load,
+/// and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_free(index: *mut OnikaIndex) {
+    if !index.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(index))));
+    }
+}
+
+/// Sketching parameters of an index.
+///
+/// # Safety
+/// `index` must be a live index. `out` must point to writable memory for an
+/// onika_params.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_params(index: *const OnikaIndex, out: *mut Params) -> OnikaStatus {
+    guard(|| {
+        *get_mut(out, "out")? = get_ref(index, "index")?.index.sketcher().params();
+        Ok(())
+    })
+}
+
+/// Number of genomes of an index, 0 if `index` is NULL.
+///
+/// # Safety
+/// `index` must be NULL or a live index.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_nb_genomes(index: *const OnikaIndex) -> usize {
+    match index.as_ref() {
+        Some(index) => index.index.nb_genomes(),
+        None => 0,
+    }
+}
+
+/// Copy the name of genome `gid` to `buffer` like snprintf: at most
+/// `buffer_len` bytes are written, NUL included, and the length of the whole
+/// name goes to `name_len` when it is not NULL.
+///
+/// # Safety
+/// `index` must be a live index. `buffer` must be writable for `buffer_len`
+/// bytes, it can be NULL when `buffer_len` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_genome_name(
+    index: *const OnikaIndex,
+    gid: usize,
+    buffer: *mut c_char,
+    buffer_len: usize,
+    name_len: *mut usize,
+) -> OnikaStatus {
+    guard(|| {
+        let index = &get_ref(index, "index")?.index;
+        let name = index.name(gid).ok_or_else(|| {
+            Error::InvalidParameters(format!("genome {} out of an index of {} genomes", gid, index.nb_genomes()))
+        })?;
+        let name = name.as_bytes();
+        if buffer_len > 0 {
+            let buffer = slice::from_raw_parts_mut(get_mut(buffer, "buffer")? as *mut c_char as *mut u8, buffer_len);
+            let copied = name.len().min(buffer_len - 1);
+            buffer[..copied].copy_from_slice(&name[..copied]);
+            buffer[copied] = 0;
+        }
+        if let Some(name_len) = name_len.as_mut() {
+            *name_len = name.len();
+        }
+        Ok(())
+    })
+}
+
+/// Add a sketched genome to the index, under `name` if not NULL. Its Gid
+/// goes to `gid` when it is not NULL.
+///
+/// # Safety
+/// `index` and `sketch` must be live objects, `name` NULL or a NUL
+/// terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_insert(
+    index: *mut OnikaIndex,
+    sketch: *const OnikaSketch,
+    name: *const c_char,
+    gid: *mut usize,
+) -> OnikaStatus {
+    guard(|| {
+        let index = &mut get_mut(index, "index")?.index;
+        let sketch = get_ref(sketch, "sketch")?;
+        let mut metadata = sketch.metadata.clone();
+        if !name.is_null() {
+            metadata.name = get_str(name, "name")?.to_string();
+        }
+        let id = index.insert(&sketch.sketch, metadata)?;
+        if let Some(gid) = gid.as_mut() {
+            *gid = id;
+        }
+        Ok(())
+    })
+}
+
+/// Fewest sketch positions a genome must share with a query to be reported.
+///
+/// # Safety
+/// `index` must be a live index.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_set_min_score(index: *mut OnikaIndex, min_score: u32) -> OnikaStatus {
+    guard(|| {
+        get_mut(index, "index")?.index.set_min_score(min_score);
+        Ok(())
+    })
+}
+
+/// The `top` genomes of the index (all of them if `top` is 0) sharing at
+/// least the minimum score of sketch positions with `sketch`, best first.
+///
+/// # Safety
+/// `index` and `sketch` must be live objects. `out` must point to writable
+/// memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn onika_index_query(
+    index: *const OnikaIndex,
+    sketch: *const OnikaSketch,
+    top: usize,
+    out: *mut *mut OnikaResult,
+) -> OnikaStatus {
+    guard(|| {
+        let out = get_out(out)?;
+        let index = &get_ref(index, "index")?.index;
+        let sketch = get_ref(sketch, "sketch")?;
+        let top = if top == 0 { None } else { Some(top) };
+        let hits = index.query(&sketch.sketch, top)?;
+        *out = Box::into_raw(Box::new(OnikaResult { hits }));
+        Ok(())
+    })
+}
+
+/// Sketch a FASTA or FASTQ file with the parameters of `index`.
+///
+/// # Safety
+/// `index` must be a live index, `path` a NUL terminated string. `out` must
+/// point to writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn onika_sketch_file(
+    index: *const OnikaIndex,
+    path: *const c_char,
+    out: *mut *mut OnikaSketch,
+) -> OnikaStatus {
+    guard(|| {
+        let out = get_out(out)?;
+        let sketcher = get_ref(index, "index")?.index.sketcher();
+        let (sketch, metadata) = sketcher.sketch_genome(get_str(path, "path")?)?;
+        *out = Box::into_raw(Box::new(OnikaSketch { sketch, metadata }));
+        Ok(())
+    })
+}
+
+/// Sketch `len` bytes of FASTA text, or of a bare sequence, with the
+/// parameters of `index`. `name` (NULL for none) names the genome.
+///
+/// # Safety
+/// `index` must be a live index, `name` NULL or a NUL terminated string.
+/// `data` must be readable for `len` bytes, it can be NULL when `len` is 0.
+/// `out` must point to writable memory for a pointer.
+#[no_mangle]
+pub unsafe extern "C" fn onika_sketch_buffer(
+    index: *const OnikaIndex,
+    name: *const c_char,
+    data: *const c_char,
+    len: usize,
+    out: *mut *mut OnikaSketch,
+) -> OnikaStatus {
+    guard(|| {
+        let out = get_out(out)?;
+        let sketcher = get_ref(index, "index")?.index.sketcher();
+        let name = if name.is_null() { "" } else { get_str(name, "name")? };
+        let data = if len == 0 {
+            &[][..]
+        } else {
+            slice::from_raw_parts(get_ref(data, "data")? as *const c_char as *const u8, len)
+        };
+        let sequences = sketcher::read_fasta(data)?;
+        let (sketch, mut metadata) = sketcher.sketch_records(name, &sequences)?;
+        metadata.paths.clear();
+        *out = Box::into_raw(Box::new(OnikaSketch { sketch, metadata }));
+        Ok(())
+    })
+}
+
+/// Free a sketch, NULL is ignored.
+///
+/// # Safety
+/// `sketch` must be NULL or come from onika_sketch_file or
+/// onika_sketch_buffer, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn onika_sketch_free(sketch: *mut OnikaSketch) {
+    if !sketch.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(sketch))));
+    }
+}
+
+/// Hits of a query by decreasing count, their number goes to `len`. The array
+/// belongs to `result` and stays valid until onika_result_free, it is NULL
+/// when there is no hit or `result` is NULL.
+///
+/// # Safety
+/// `result` must be NULL or a live result. `len` must point to writable
+/// memory for a size_t.
+#[no_mangle]
+pub unsafe extern "C" fn onika_result_hits(result: *const OnikaResult, len: *mut usize) -> *const Hit {
+    let hits = match result.as_ref() {
+        Some(result) => &result.hits[..],
+        None => &[],
+    };
+    if let Some(len) = len.as_mut() {
+        *len = hits.len();
+    }
+    if hits.is_empty() {
+        ptr::null()
+    } else {
+        hits.as_ptr()
+    }
+}
+
+/// Free a query result, NULL is ignored.
+///
+/// # Safety
+/// `result` must be NULL or come from onika_index_query, and not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn onika_result_free(result: *mut OnikaResult) {
+    if !result.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(result))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: Params = Params { k: 15, lf: 10, w: 8, e: 20000 };
+
+    fn sequence(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                b"ACGT"[(state >> 62) as usize]
+            })
+            .collect()
+    }
+
+    fn c_string(value: &str) -> CString {
+        CString::new(value).unwrap()
+    }
+
+    unsafe fn new_index(params: &Params) -> *mut OnikaIndex {
+        let mut index = ptr::null_mut();
+        assert_eq!(onika_index_new(params, 32, 16, &mut index), OnikaStatus::Ok);
+        index
+    }
+
+    unsafe fn sketch(index: *const OnikaIndex, data: &[u8]) -> *mut OnikaSketch {
+        let mut sketch = ptr::null_mut();
+        let status = onika_sketch_buffer(index, ptr::null(), data.as_ptr() as *const c_char, data.len(), &mut sketch);
+        assert_eq!(status, OnikaStatus::Ok);
+        sketch
+    }
+
+    unsafe fn hits(index: *const OnikaIndex, sketch: *const OnikaSketch) -> Vec<(usize, u32)> {
+        let mut result = ptr::null_mut();
+        assert_eq!(onika_index_query(index, sketch, 0, &mut result), OnikaStatus::Ok);
+        let mut len = 0;
+        let hits = onika_result_hits(result, &mut len);
+        let hits = if len == 0 { Vec::new() } else { slice::from_raw_parts(hits, len).iter().map(|hit| (hit.gid, hit.count)).collect() };
+        onika_result_free(result);
+        hits
+    }
+
+    unsafe fn name(index: *const OnikaIndex, gid: usize) -> String {
+        let mut buffer = [0 as c_char; 64];
+        assert_eq!(onika_index_genome_name(index, gid, buffer.as_mut_ptr(), buffer.len(), ptr::null_mut()), OnikaStatus::Ok);
+        CStr::from_ptr(buffer.as_ptr()).to_str().unwrap().to_string()
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(onika_last_error()).to_str().unwrap().to_string() }
+    }
+
+    #[test]
+    fn index_survives_dump_and_load() {
+        let path = std::env::temp_dir().join(format!("onika-ffi-{}.idx", std::process::id()));
+        let path = c_string(path.to_str().unwrap());
+        unsafe {
+            let index = new_index(&PARAMS);
+            let sketches = [sketch(index, &sequence(1, 20000)), sketch(index, &sequence(2, 20000))];
+            let mut gid = usize::MAX;
+            assert_eq!(onika_index_insert(index, sketches[0], c_string("first").as_ptr(), &mut gid), OnikaStatus::Ok);
+            assert_eq!(gid, 0);
+            assert_eq!(onika_index_insert(index, sketches[1], ptr::null(), &mut gid), OnikaStatus::Ok);
+            assert_eq!(gid, 1);
+            assert_eq!(onika_index_nb_genomes(index), 2);
+            let before = hits(index, sketches[1]);
+            assert_eq!(before[0], (1, 1 << PARAMS.lf));
+            assert!(before.iter().all(|&(gid, count)| gid == 1 || count < 1 << PARAMS.lf));
+
+            assert_eq!(onika_index_dump(index, path.as_ptr()), OnikaStatus::Ok);
+            onika_index_free(index);
+            let mut loaded = ptr::null_mut();
+            assert_eq!(onika_index_load(path.as_ptr(), &mut loaded), OnikaStatus::Ok);
+            let mut params = Params::default();
+            assert_eq!(onika_index_params(loaded, &mut params), OnikaStatus::Ok);
+            assert_eq!(params, PARAMS);
+            assert_eq!(onika_index_nb_genomes(loaded), 2);
+            assert_eq!(name(loaded, 0), "first");
+            assert_eq!(name(loaded, 1), "");
+            assert_eq!(hits(loaded, sketches[1]), before);
+
+            sketches.iter().for_each(|&sketch| onika_sketch_free(sketch));
+            onika_index_free(loaded);
+            onika_index_free(ptr::null_mut());
+            onika_sketch_free(ptr::null_mut());
+            onika_result_free(ptr::null_mut());
+        }
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn genome_name_is_copied_like_snprintf() {
+        unsafe {
+            let index = new_index(&PARAMS);
+            let sketch = sketch(index, &sequence(1, 5000));
+            assert_eq!(onika_index_insert(index, sketch, c_string("genome").as_ptr(), ptr::null_mut()), OnikaStatus::Ok);
+
+            let mut len = 0;
+            assert_eq!(onika_index_genome_name(index, 0, ptr::null_mut(), 0, &mut len), OnikaStatus::Ok);
+            assert_eq!(len, 6);
+            let mut buffer = [1 as c_char; 4];
+            assert_eq!(onika_index_genome_name(index, 0, buffer.as_mut_ptr(), buffer.len(), &mut len), OnikaStatus::Ok);
+            assert_eq!(CStr::from_ptr(buffer.as_ptr()).to_bytes(), b"gen");
+            assert_eq!(len, 6);
+            assert_eq!(name(index, 0), "genome");
+            assert_eq!(onika_index_genome_name(index, 1, ptr::null_mut(), 0, &mut len), OnikaStatus::InvalidParameters);
+            assert!(last_error().contains("genome 1 out of an index of 1 genomes"));
+
+            onika_sketch_free(sketch);
+            onika_index_free(index);
+        }
+    }
+
+    #[test]
+    fn null_arguments_are_invalid_parameters() {
+        unsafe {
+            let index = new_index(&PARAMS);
+            let sketch = sketch(index, &sequence(1, 5000));
+            let mut out_index = ptr::null_mut();
+            let mut out_sketch = ptr::null_mut();
+            let mut out_result = ptr::null_mut();
+
+            assert_eq!(onika_params_default(ptr::null_mut()), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_index_new(&PARAMS, 32, 16, ptr::null_mut()), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_index_load(ptr::null(), &mut out_index), OnikaStatus::InvalidParameters);
+            assert!(out_index.is_null());
+            assert_eq!(onika_index_dump(ptr::null(), c_string("x").as_ptr()), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_index_dump(index, ptr::null()), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_index_insert(ptr::null_mut(), sketch, ptr::null(), ptr::null_mut()), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_index_insert(index, ptr::null(), ptr::null(), ptr::null_mut()), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_index_query(index, ptr::null(), 0, &mut out_result), OnikaStatus::InvalidParameters);
+            assert!(out_result.is_null());
+            assert_eq!(onika_index_set_min_score(ptr::null_mut(), 1), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_sketch_file(index, ptr::null(), &mut out_sketch), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_sketch_buffer(ptr::null(), ptr::null(), ptr::null(), 0, &mut out_sketch), OnikaStatus::InvalidParameters);
+            assert_eq!(onika_sketch_buffer(index, ptr::null(), ptr::null(), 10, &mut out_sketch), OnikaStatus::InvalidParameters);
+            assert!(out_sketch.is_null());
+            assert!(last_error().contains("is NULL"));
+            assert_eq!(onika_index_nb_genomes(ptr::null()), 0);
+            assert_eq!(onika_index_nb_genomes(index), 0);
+
+            assert_eq!(onika_index_new(&PARAMS, 8, 16, &mut out_index), OnikaStatus::InvalidParameters);
+            assert!(out_index.is_null());
+            onika_sketch_free(sketch);
+            onika_index_free(index);
+        }
+    }
+
+    #[test]
+    fn sketches_of_other_parameters_are_refused() {
+        unsafe {
+            let index = new_index(&PARAMS);
+            let other = new_index(&Params { lf: 8, ..PARAMS });
+            let sketch = sketch(other, &sequence(1, 5000));
+            let mut result = ptr::null_mut();
+            assert_eq!(onika_index_insert(index, sketch, ptr::null(), ptr::null_mut()), OnikaStatus::SketchSize);
+            assert_eq!(onika_index_query(index, sketch, 0, &mut result), OnikaStatus::SketchSize);
+            assert!(result.is_null());
+            assert_eq!(onika_index_nb_genomes(index), 0);
+            onika_sketch_free(sketch);
+            onika_index_free(other);
+            onika_index_free(index);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::index::Gid;
+use crate::similarity::Similarity;
+
+/// Layout of a graph of genomes, edges weighted by the Jaccard estimate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    Tsv,     // edge list, one source/target pair per line with a header line
+    Graphml, // GraphML, directed
+    Gexf,    // GEXF 1.3, directed
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GraphFormat, String> {
+        match s {
+            "tsv" => Ok(GraphFormat::Tsv),
+            "graphml" => Ok(GraphFormat::Graphml),
+            "gexf" => Ok(GraphFormat::Gexf),
+            _ => Err(format!("unknown graph format '{}', expected tsv, graphml or gexf", s)),
+        }
+    }
+}
+
+impl fmt::Display for GraphFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            GraphFormat::Tsv => "tsv",
+            GraphFormat::Graphml => "graphml",
+            GraphFormat::Gexf => "gexf",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Write the graph over the genomes named `names`, `edges[i]` holding the
+/// edges from genome i
+pub fn write_graph<W: Write>(out: &mut W, format: GraphFormat, names: &[&str], edges: &[Vec<(Gid, Similarity)>]) -> io::Result<()> {
+    match format {
+        GraphFormat::Tsv => write_edge_list(out, names, edges),
+        GraphFormat::Graphml => write_graphml(out, names, edges),
+        GraphFormat::Gexf => write_gexf(out, names, edges),
+    }
+}
+
+fn write_edge_list<W: Write>(out: &mut W, names: &[&str], edges: &[Vec<(Gid, Similarity)>]) -> io::Result<()> {
+    let mut columns = vec!["source", "target"];
+    columns.extend(Similarity::COLUMNS);
+    writeln!(out, "{}", columns.join("\t"))?;
+    for (i, genome_edges) in edges.iter().enumerate() {
+        for (j, similarity) in genome_edges {
+            writeln!(out, "{}\t{}\t{}", names[i], names[*j], similarity.values().join("\t"))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_graphml<W: Write>(out: &mut W, names: &[&str], edges: &[Vec<(Gid, Similarity)>]) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#)?;
+    writeln!(out, r#"  <key id="name" for="node" attr.name="name" attr.type="string"/>"#)?;
+    writeln!(out, r#"  <key id="weight" for="edge" attr.name="weight" attr.type="double"/>"#)?;
+    writeln!(out, r#"  <key id="ani" for="edge" attr.name="ani" attr.type="double"/>"#)?;
+    writeln!(out, r#"  <key id="shared" for="edge" attr.name="shared" attr.type="int"/>"#)?;
+    writeln!(out, r#"  <graph id="genomes" edgedefault="directed">"#)?;
+    for (i, name) in names.iter().enumerate() {
+        writeln!(out, r#"    <node id="n{}"><data key="name">{}</data></node>"#, i, xml_escape(name))?;
+    }
+    for (i, genome_edges) in edges.iter().enumerate() {
+        for (j, similarity) in genome_edges {
+            writeln!(
+                out,
+                r#"    <edge source="n{}" target="n{}"><data key="weight">{:.6}</data><data key="ani">{:.6}</data><data key="shared">{}</data></edge>"#,
+                i, j, similarity.jaccard, similarity.ani, similarity.shared
+            )?;
+        }
+    }
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</graphml>")
+}
+
+fn write_gexf<W: Write>(out: &mut W, names: &[&str], edges: &[Vec<(Gid, Similarity)>]) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(out, r#"<gexf xmlns="http://gexf.net/1.3" version="1.3">"#)?;
+    writeln!(out, r#"  <graph defaultedgetype="directed">"#)?;
+    writeln!(out, r#"    <attributes class="edge">"#)?;
+    writeln!(out, r#"      <attribute id="ani" title="ani" type="double"/>"#)?;
+    writeln!(out, r#"      <attribute id="shared" title="shared" type="integer"/>"#)?;
+    writeln!(out, "    </attributes>")?;
+    writeln!(out, "    <nodes>")?;
+    for (i, name) in names.iter().enumerate() {
+        writeln!(out, r#"      <node id="{}" label="{}"/>"#, i, xml_escape(name))?;
+    }
+    writeln!(out, "    </nodes>")?;
+    writeln!(out, "    <edges>")?;
+    let mut id = 0;
+    for (i, genome_edges) in edges.iter().enumerate() {
+        for (j, similarity) in genome_edges {
+            writeln!(
+                out,
+                r#"      <edge id="{}" source="{}" target="{}" weight="{:.6}"><attvalues><attvalue for="ani" value="{:.6}"/><attvalue for="shared" value="{}"/></attvalues></edge>"#,
+                id, i, j, similarity.jaccard, similarity.ani, similarity.shared
+            )?;
+            id += 1;
+        }
+    }
+    writeln!(out, "    </edges>")?;
+    writeln!(out, "  </graph>")?;
+    writeln!(out, "</gexf>")
+}
+
+fn xml_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::Estimator;
+    use crate::sketcher::Params;
+
+    const NAMES: [&str; 3] = ["plain", "a<b>&\"c\"", "it's"];
+
+    fn graph(format: GraphFormat) -> String {
+        let estimator = Estimator::new(Params::default(), Params::default().sketch_size());
+        let edge = |gid, shared| (gid, estimator.estimate(shared, 5000, 5000));
+        let edges = vec![vec![edge(1, 900), edge(2, 10)], vec![edge(0, 900)], vec![]];
+        let mut out = Vec::new();
+        write_graph(&mut out, format, &NAMES, &edges).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // Every element is closed in order, and text and attributes hold no raw
+    // '<' and only known entities
+    fn assert_well_formed(xml: &str) {
+        let mut open: Vec<&str> = Vec::new();
+        let mut rest = xml.strip_prefix("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n").unwrap();
+        while let Some(start) = rest.find('<') {
+            assert_entities(&rest[..start]);
+            let end = start + rest[start..].find('>').unwrap();
+            let tag = &rest[start + 1..end];
+            assert!(!tag.contains('<'), "{}", tag);
+            if let Some(name) = tag.strip_prefix('/') {
+                assert_eq!(open.pop(), Some(name));
+            } else {
+                let name = tag.split([' ', '/']).next().unwrap();
+                let quoted: Vec<&str> = tag.split('"').skip(1).step_by(2).collect();
+                quoted.iter().for_each(|value| assert_entities(value));
+                if !tag.ends_with('/') {
+                    open.push(name);
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        assert!(open.is_empty(), "unclosed {:?}", open);
+        assert!(rest.trim().is_empty());
+    }
+
+    fn assert_entities(text: &str) {
+        for (i, _) in text.match_indices('&') {
+            let entity = &text[i..i + text[i..].find(';').unwrap() + 1];
+            assert!(["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"].contains(&entity), "{}", entity);
+        }
+        assert!(!text.contains('>') && !text.contains('"'), "{}", text);
+    }
+
+    #[test]
+    fn edge_list_has_one_line_per_edge() {
+        let tsv = graph(GraphFormat::Tsv);
+        let lines: Vec<Vec<&str>> = tsv.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0][..3], ["source", "target", "shared"]);
+        assert!(lines.iter().all(|line| line.len() == 2 + Similarity::COLUMNS.len()));
+        let pairs: Vec<(&str, &str, &str)> = lines[1..].iter().map(|line| (line[0], line[1], line[2])).collect();
+        assert_eq!(pairs, [(NAMES[0], NAMES[1], "900"), (NAMES[0], NAMES[2], "10"), (NAMES[1], NAMES[0], "900")]);
+    }
+
+    #[test]
+    fn graphml_is_well_formed_and_escaped() {
+        let graphml = graph(GraphFormat::Graphml);
+        assert_well_formed(&graphml);
+        assert!(graphml.contains(r#"<node id="n1"><data key="name">a&lt;b&gt;&amp;&quot;c&quot;</data></node>"#));
+        assert!(graphml.contains(r#"<data key="name">it&apos;s</data>"#));
+        assert_eq!(graphml.matches("<node ").count(), 3);
+        assert_eq!(graphml.matches("<edge ").count(), 3);
+        assert!(graphml.contains(r#"<edge source="n0" target="n2">"#));
+    }
+
+    #[test]
+    fn gexf_is_well_formed_and_escaped() {
+        let gexf = graph(GraphFormat::Gexf);
+        assert_well_formed(&gexf);
+        assert!(gexf.contains(r#"<node id="1" label="a&lt;b&gt;&amp;&quot;c&quot;"/>"#));
+        assert_eq!(gexf.matches("<node ").count(), 3);
+        assert_eq!(gexf.matches("<edge ").count(), 3);
+        assert!(gexf.contains(r#"<edge id="2" source="1" target="0" "#));
+    }
+}
@@ -1,524 +1,725 @@
 use std::fs::File;
+use std::ops::Range;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::io::{BufReader, BufRead};
-use std::string::String;
-use std::vec::Vec;
-use arrayvec::ArrayVec;
+use std::io::{BufReader, BufRead, BufWriter, Write};
 use rayon::prelude::*;
+use std::vec::Vec;
+use crate::error::{Error, Result};
+use crate::duplicates::{DuplicateFinder, DuplicateKind, DuplicatePolicy};
+use crate::inputs::{BuildEvent, InputIssue, InputReport};
+use crate::metadata::{self, GenomeMetadata};
+use crate::novelty::NoveltyReport;
+use crate::output::ResultWriter;
+use crate::query::{self, Hit, QueryResult};
+use crate::similarity::{Estimator, Similarity};
+use crate::sketch_store::SketchStore;
+use crate::sketcher::{Params, Sketch, Sketcher};
+use crate::storage::{self, Checksummed, Header};
+use crate::width::{self, IndexInt};
+
+/// Genome id as seen by callers, the buckets store it as a G
+pub type Gid = usize;
+
+/// Values of the --dist matrix, row i and column j comparing genome i to genome j
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DistanceMatrix {
+    Containment, // containment of genome i in genome j, asymmetric
+    Jaccard,     // Jaccard distance 1 - J, symmetric
+    Mash,        // Mash distance from the Jaccard estimate, symmetric
+}
 
-type Kmer = u64;
-type Gid = u32;
-
-const MUTEX_NUMBER: usize = 65536;
+impl DistanceMatrix {
+    pub fn is_symmetric(&self) -> bool {
+        !matches!(self, DistanceMatrix::Containment)
+    }
+}
 
-pub struct Index {
-    // CONSTANTS
-    k: u32,                         // kmer size
-    f: u32,                         // fingerprint used
-    w: u32,                         // fingerprint size
-    m: u32,                         // Minhash size
-    genome_numbers: Arc<Mutex<u32>>, // Number of genomes
-    e: u32,                         // Expected genome size (5000000)
+/// Inverted index of genome sketches: for each fingerprint, the genomes and
+/// sketch positions holding it. G is the integer type of the genome ids
+/// stored in the buckets, P the one of the sketch positions.
+pub struct Index<G: IndexInt = u32, P: IndexInt = u16> {
+    sketcher: Sketcher,             // sketching parameters, shared with the queries
+    genome_numbers: usize,          // Number of genomes, only grows through &mut self
     fingerprint_range: u64,         // 2^w
-
-    maximal_remainder: u32,    // 2^H-1
-    lf: u32,                   // log2(F)
-    mask_fingerprint: u64,     // 2^(64-lf)-1
-    mi: u64,                   // -1
-    offset_update_kmer: u64,
-    min_score: u32,
-    filename: String,
-    buckets: Vec<Vec<Gid>>,             // The details of "Buckets" and "Buckets_pos" are not clear in the initial code.
-    buckets_pos: Vec<Vec<u16>>,         // For now, they are represented as 2D vectors.
-    lock: ArrayVec<[Mutex<u32>; MUTEX_NUMBER]>,
-    filenames: Arc<Mutex<Vec<String>>>,
-    file_sketches: Arc<Mutex<HashMap<String, Vec<u64>>>>,
+    min_score: u32,                 // fewest shared positions for a genome to be reported
+    shard: u32,                // this index only holds the positions of
+    nb_shards: u32,            // shard `shard` out of `nb_shards`
+    buckets: Vec<Vec<G>>,               // The details of "Buckets" and "Buckets_pos" are not clear in the initial code.
+    buckets_pos: Vec<Vec<P>>,           // For now, they are represented as 2D vectors.
+    metadata: Vec<GenomeMetadata>,     // name, paths and statistics of each genome
+    sketches: SketchStore,              // sketch of each genome, restricted to the shard positions
 }
 
-impl Index {
-    pub fn new(lf: u32, k: u32, w: u32, e: u32, filename: String) -> Index {
-        let f = 1u32 << lf;
-        let fingerprint_range = 1u64 << w;
-        let offset_update_kmer = 1u64 << (2 * k);
-        let mi = !0u64;
-        let maximal_remainder = (1 << w) - 1;
-        let mask_fingerprint = (1u64 << (64 - lf)) - 1;
-        let min_score = 0; // Assuming min_score should be initialized to 0
-
-        let mut lock = ArrayVec::new();
-        for _ in 0..MUTEX_NUMBER {
-            lock.push(Mutex::new(0));
-        }
-        let buckets = vec![Vec::<Gid>::new(); fingerprint_range as usize];
-        let buckets_pos = vec![Vec::<u16>::new(); fingerprint_range as usize];
-        Index {
-            k,
-            f,
-            w,
-            m: 0, // This needs to be set to the correct initial value
-            genome_numbers: Arc::new(Mutex::new(0)),
-            e,
+impl<G: IndexInt, P: IndexInt> Index<G, P> {
+    /// Empty index. Fails on parameters the index cannot represent,
+    /// including a sketch too large for the position width P.
+    pub fn new(params: Params) -> Result<Index<G, P>> {
+        let sketcher = Sketcher::new(params)?;
+        width::check_widths(G::BYTES * 8, P::BYTES * 8, params.lf).map_err(Error::InvalidParameters)?;
+        let fingerprint_range = params.fingerprint_range();
+        let min_score = 0;
+
+        Ok(Index {
+            sketcher,
+            genome_numbers: 0,
             fingerprint_range,
-            maximal_remainder,
-            lf,
-            mask_fingerprint,
-            mi,
-            offset_update_kmer,
             min_score,
-            filename,
-            //buckets: Vec::new(),
-            //buckets_pos: Vec::new(),
+            shard: 0,
+            nb_shards: 1,
             buckets: vec![Vec::new(); fingerprint_range as usize], // initialize here
             buckets_pos: vec![Vec::new(); fingerprint_range as usize], // initialize here
-            lock,
-            filenames: Arc::new(Mutex::new(Vec::new())),
-            file_sketches: Arc::new(Mutex::new(HashMap::new())),
-        }
+            metadata: Vec::new(),
+            sketches: SketchStore::new(params.w, params.sketch_size()),
+        })
     }
 
-
-    pub fn get_k(&self) -> u32 {
-        self.k
+    pub fn params(&self) -> Params {
+        self.sketcher.params()
     }
 
-    pub fn get_f(&self) -> u32 {
-        self.f
+    /// Sketcher making sketches that can be queried against this index
+    pub fn sketcher(&self) -> &Sketcher {
+        &self.sketcher
     }
 
-    pub fn get_fingerprint_range(&self) -> u64 {
-        self.fingerprint_range
+    /// Genomes and sketch positions holding a fingerprint, None past the
+    /// fingerprint range
+    pub fn get_bucket(&self, fingerprint: u64) -> Option<(&[G], &[P])> {
+        let genomes = self.buckets.get(fingerprint as usize)?;
+        Some((genomes, &self.buckets_pos[fingerprint as usize]))
     }
 
-    pub fn get_w(&self) -> u32 {
-        self.w
+    /// Bytes held by buckets and by buckets_pos, vector headers included
+    pub fn get_buckets_bytes(&self) -> (usize, usize) {
+        let header = std::mem::size_of::<Vec<G>>();
+        let buckets = self.buckets.iter().map(|bucket| header + bucket.capacity() * std::mem::size_of::<G>()).sum();
+        let buckets_pos = self.buckets_pos.iter().map(|bucket| header + bucket.capacity() * std::mem::size_of::<P>()).sum();
+        (buckets, buckets_pos)
     }
 
-    pub fn get_e(&self) -> u32 {
-        self.e
+    pub fn get_sketches_bytes(&self) -> usize {
+        self.sketches.get_bytes()
     }
 
-    // Destructeur
-    fn cleanup(&mut self) {
-        // Libérer la mémoire
-        self.buckets.clear();
-        self.buckets_pos.clear();
-
+    /// Restrict the index to the sketch positions [shard*F/nb_shards, (shard+1)*F/nb_shards).
+    /// Fails once genomes are inserted.
+    pub fn set_shard(&mut self, shard: u32, nb_shards: u32) -> Result<()> {
+        if self.genome_numbers > 0 {
+            return Err(Error::InvalidParameters(String::from(
+                "the shard of an index holding genomes cannot be changed",
+            )));
+        }
+        storage::check_shard(shard, nb_shards, self.params().lf).map_err(Error::InvalidParameters)?;
+        self.shard = shard;
+        self.nb_shards = nb_shards;
+        self.sketches = SketchStore::new(self.params().w, self.get_position_range().len());
+        Ok(())
     }
 
-    pub fn get_nb_genomes(&self) -> u32 {
-        *self.genome_numbers.lock().unwrap()
+    pub fn get_shard(&self) -> (u32, u32) {
+        (self.shard, self.nb_shards)
     }
 
-    pub fn exists_test(&self, name: &str) -> bool {
-        Path::new(name).exists()
+    /// Sketch positions held by this index, all of them unless it is a shard
+    pub fn get_position_range(&self) -> Range<usize> {
+        let f = self.params().sketch_size() as u64;
+        let start = self.shard as u64 * f / self.nb_shards as u64;
+        let end = (self.shard as u64 + 1) * f / self.nb_shards as u64;
+        start as usize..end as usize
     }
 
+    /// Fewest sketch positions a genome must share with a query to be
+    /// reported, saved with the index
+    pub fn get_min_score(&self) -> u32 {
+        self.min_score
+    }
 
-    // This is a placeholder implementation, you need to fill in the actual logic
-    pub fn asm_log2(&self, _x: u64) -> u64 {
-        0
+    pub fn set_min_score(&mut self, min_score: u32) {
+        self.min_score = min_score;
     }
 
-    pub fn get_data_type(&self, filename: &str) -> char {
-        if filename.find(".fq").is_some() {
-            return 'Q';
-        }
-        if filename.find(".fastq").is_some() {
-            return 'Q';
-        }
-        'A'
+    pub fn get_nb_genomes(&self) -> usize {
+        self.genome_numbers
     }
 
-    pub fn get_filename(&mut self, filestr: &str) {
-        if cfg!(debug_assertions) {
-            dbg!(filestr);
-        }
-        let file = File::open(filestr).expect("Unable to open the file");
-        let reader = BufReader::new(file);
+    /// Largest number of genomes the id width G can hold
+    pub fn get_max_genomes(&self) -> usize {
+        (G::max_value() as usize).saturating_add(1)
+    }
 
-        for line in reader.lines() {
-            if let Ok(filename) = line {
-                if cfg!(debug_assertions) {
-                    dbg!(&filename);
-                }
-                if filename.len() > 2 && self.exists_test(&filename) {
+    pub fn get_metadata(&self, gid: Gid) -> Option<&GenomeMetadata> {
+        self.metadata.get(gid)
+    }
 
-                    let id = {
+    /// Metadata of every genome, indexed by Gid
+    pub fn get_all_metadata(&self) -> &[GenomeMetadata] {
+        &self.metadata
+    }
 
-                        let mut genome_numbers = self.genome_numbers.lock().unwrap();
+    pub fn get_name(&self, gid: Gid) -> Option<&str> {
+        self.metadata.get(gid).map(|metadata| metadata.name.as_str())
+    }
 
-                        let id = *genome_numbers;
+    /// Stored sketch of a genome, Sketch::EMPTY outside of the positions of the shard
+    pub fn get_sketch(&self, gid: Gid) -> Option<Sketch> {
+        (gid < self.genome_numbers).then(|| self.stored_sketch(gid))
+    }
 
-                        *genome_numbers += 1;
+    fn stored_sketch(&self, gid: Gid) -> Sketch {
+        let mut bins = vec![Sketch::EMPTY; self.params().sketch_size()];
+        bins[self.get_position_range()].copy_from_slice(&self.sketches.get(gid));
+        Sketch::from_bins(bins)
+    }
 
-                        id
+    /// Duplicate finder knowing every genome already in the index. Sketches
+    /// are compared on all their positions: a shard only stores its own, so
+    /// the genomes of a loaded shard are only known by path.
+    pub fn duplicate_finder(&self, policy: DuplicatePolicy) -> DuplicateFinder {
+        let mut duplicates = DuplicateFinder::new(policy);
+        let whole = self.nb_shards == 1;
+        for gid in 0..self.genome_numbers {
+            let sketch = if whole { self.sketches.get(gid) } else { Vec::new() };
+            duplicates.add(gid, &self.metadata[gid].paths, &sketch);
+        }
+        duplicates
+    }
 
-                    };
-                    println!("Adding file: '{}'", filename);
-                    self.insert_file(&filename, id);
-                    println!("File: '{}' added", filename);
+    /// Index the genomes listed in a file of files. Inputs that cannot be
+    /// indexed go to `inputs`, which stops the build in strict mode. With
+    /// `novelty`, each genome is first queried against the index. Each step
+    /// is handed to `progress`.
+    pub fn get_filename(
+        &mut self,
+        filestr: &str,
+        duplicates: &mut DuplicateFinder,
+        inputs: &mut InputReport,
+        mut novelty: Option<&mut NoveltyReport>,
+        progress: &mut dyn FnMut(BuildEvent),
+    ) -> Result<()> {
+        let reader = BufReader::new(File::open(filestr)?);
+
+        for (line_number, line) in reader.lines().enumerate() {
+            let (filename, tags, ignored) = metadata::parse_fof_line(&line?);
+            for field in &ignored {
+                progress(BuildEvent::IgnoredTag(&filename, field));
+            }
+            if filename.is_empty() {
+                continue;
+            }
+            let (sketch, metadata) = match self.sketcher.sketch_input(&filename) {
+                Ok(genome) => genome,
+                Err((kind, message)) => {
+                    let issue = inputs.add(InputIssue {
+                        line: line_number + 1,
+                        path: filename,
+                        kind,
+                        message,
+                    })?;
+                    progress(BuildEvent::Skipped(issue));
+                    continue;
                 }
+            };
+            let duplicate = match duplicates.find_path(&filename) {
+                Some(original) => Some((original, DuplicateKind::Path)),
+                None => duplicates
+                    .sketch_candidates(sketch.bins())
+                    .iter()
+                    .copied()
+                    .find(|&gid| self.sketches.holds(gid, &sketch.bins()[self.get_position_range()]))
+                    .map(|original| (original, DuplicateKind::Sketch)),
+            };
+            if let Some((original, kind)) = duplicate {
+                let skip = duplicates.handle(&filename, original, &mut self.metadata[original], kind);
+                if let Some(found) = duplicates.last() {
+                    progress(BuildEvent::Duplicate(found, duplicates.policy()));
+                }
+                if skip {
+                    continue;
+                }
+            }
+
+            if let Some(report) = novelty.as_deref_mut() {
+                progress(BuildEvent::Novelty(report.add(self, &filename, &sketch, metadata.cardinality)?));
             }
+            progress(BuildEvent::Adding(&filename));
+            let id = self.insert_genome(&sketch, metadata)?;
+            self.metadata[id].add_tags(tags);
+            duplicates.add(id, std::slice::from_ref(&filename), sketch.bins());
+            progress(BuildEvent::Added(&filename, id));
         }
+        Ok(())
     }
 
-
-    pub fn insert_sketch(&mut self, sketch: &[u64], genome_id: u32) {
-        let mutex_number = self.lock.len() as u64;
+    // Insertion goes through &mut self, no lock is needed
+    fn insert_sketch(&mut self, sketch: &[u64], genome_id: Gid) {
+        let range = self.get_position_range();
         sketch.iter().enumerate().for_each(|(i, &val)| {
-            if cfg!(debug_assertions) {
-                dbg!(val);
+            if !range.contains(&i) {
+                return;
             }
-            // Check if val is within the size of buckets and buckets_pos
-            if val < self.fingerprint_range && (val as usize) < self.buckets.len() && (val as usize) < self.buckets_pos.len() {
-                let lock_index = (val + (i as u64) * self.fingerprint_range) % mutex_number;
-                // Check if lock_index is within the size of self.lock
-                if (lock_index as usize) < self.lock.len() {
-                    let lock = &self.lock[lock_index as usize];
-                    if cfg!(debug_assertions) {
-                        dbg!(lock_index);
-                    }
-                    let guard = lock.lock().unwrap();
-                    if cfg!(debug_assertions) {
-                        dbg!(&self.buckets.len(), &val);
-                    }
-                    self.buckets[val as usize].push(genome_id);
-                    self.buckets_pos[val as usize].push(i as u16);
-                    drop(guard);
-                }
+            if val < self.fingerprint_range {
+                self.buckets[val as usize].push(G::from_usize(genome_id));
+                self.buckets_pos[val as usize].push(P::from_usize(i));
             }
         });
     }
 
+    /// Store the sketch and metadata of a genome and add its postings.
+    /// Returns the identifier given to the genome, the next one in order.
+    pub fn insert_genome(&mut self, sketch: &Sketch, metadata: GenomeMetadata) -> Result<Gid> {
+        if self.genome_numbers >= self.get_max_genomes() {
+            return Err(Error::IndexFull {
+                id_bits: G::BYTES * 8,
+                max_genomes: self.get_max_genomes(),
+            });
+        }
+        self.check_sketch(sketch)?;
+        let gid = self.genome_numbers;
+        self.genome_numbers += 1;
+        self.metadata.push(metadata);
+        self.sketches.insert(gid, &sketch.bins()[self.get_position_range()]);
+        self.insert_sketch(sketch.bins(), gid);
+        Ok(gid)
+    }
 
-
-    pub fn insert_file(&mut self, filestr: &str, identifier: u32) {
-        if cfg!(debug_assertions) {
-            dbg!(filestr, identifier);
+    fn check_sketch(&self, sketch: &Sketch) -> Result<()> {
+        let expected = self.params().sketch_size();
+        if sketch.nb_bins() != expected {
+            return Err(Error::SketchSize {
+                expected,
+                found: sketch.nb_bins(),
+            });
         }
-        //let r#type = self.get_data_type(filestr); // Utilise self pour appeler la méthode de la structure
-        let file = File::open(filestr).expect("Unable to open the file");
-        let reader = BufReader::new(file);
-
-        for line in reader.lines() {
-            if let Ok(ref_value) = line {
-                dbg!(&ref_value);
-                let mut ref_value = ref_value.to_string();
-                //let mut kmer_sketch = Vec::new();
-                let mut sketch = vec![u64::MAX; self.f as usize];
-
-                if ref_value.len() > self.k as usize {
-                    self.compute_sketch(&mut ref_value, &mut sketch); // Assuming this function is defined elsewhere
-                }
+        Ok(())
+    }
 
-                {
-                    let file_sketches = Arc::clone(&self.file_sketches);
-                    let mut file_sketches = file_sketches.lock().unwrap();
-                    dbg!(&ref_value, &sketch);
-                    file_sketches.insert(ref_value.clone(), sketch.clone());
-                }
+    /// Number of sketch positions each genome shares with `sketch`.
+    /// Read only and lock free, a shared &Index can be queried from many threads.
+    pub fn query_sketch(&self, sketch: &Sketch) -> Result<QueryResult> {
+        self.check_sketch(sketch)?;
+        let mut result = vec![0; self.genome_numbers];
+
+        for (i, val) in sketch.bins().iter().enumerate() {
+            if *val < self.fingerprint_range {
+                let bucket = &self.buckets[*val as usize];
+                let bucket_pos = &self.buckets_pos[*val as usize];
 
-                self.insert_sketch(&sketch, identifier); // Assuming this function is defined elsewhere
+                for j in 0..bucket.len() {
+                    if bucket_pos[j].to_usize() == i {
+                        result[bucket[j].to_usize()] += 1;
+                    }
+                }
             }
         }
+
+        Ok(QueryResult::new(result))
     }
 
-    pub fn compute_sketch(&self, reference: &str, sketch: &mut Vec<u64>) {
-        if sketch.len() != self.f as usize {
-            *sketch = vec![u64::MAX; self.f as usize];
-        }
-        println!("------------------SKETCH--------------------");
-        let mut empty_cell = self.f;
-        let mut S_kmer = self.str2numstrand(&reference[0..(self.k as usize - 1)]);
-        let mut RC_kmer = self.rcb(S_kmer);
-
-        for i in 0..(reference.len() - self.k as usize) {
-            self.update_kmer(&mut S_kmer, reference.chars().nth((i + self.k as usize - 1) as usize).unwrap());
-            self.update_kmer_RC(&mut RC_kmer, reference.chars().nth((i + self.k as usize - 1) as usize).unwrap());
-
-            let canon = S_kmer.min(RC_kmer);
-            let hashed = self.revhash64(canon);
-            let bucket_id = self.unrevhash64(canon) >> (64 - self.lf);
-            let fp = hashed;
-
-            if sketch[bucket_id as usize] == u64::MAX {
-                empty_cell -= 1;
-                sketch[bucket_id as usize] = fp;
-            } else if sketch[bucket_id as usize] > fp {
-                sketch[bucket_id as usize] = fp;
-            }
+    /// query_sketch where a match at position i counts `weights[i]` times,
+    /// `weights` holding one value per position of the sketch. Bootstrap
+    /// replicates resample the positions this way.
+    pub fn query_sketch_weighted(&self, sketch: &Sketch, weights: &[u32]) -> Result<QueryResult> {
+        self.check_sketch(sketch)?;
+        if weights.len() != sketch.nb_bins() {
+            return Err(Error::SketchSize {
+                expected: sketch.nb_bins(),
+                found: weights.len(),
+            });
         }
+        let mut result = vec![0; self.genome_numbers];
 
-        self.sketch_densification(sketch, empty_cell);
+        for (i, val) in sketch.bins().iter().enumerate() {
+            if *val < self.fingerprint_range && weights[i] > 0 {
+                let bucket = &self.buckets[*val as usize];
+                let bucket_pos = &self.buckets_pos[*val as usize];
 
-        for i in 0..sketch.len() {
-            sketch[i] = self.get_perfect_fingerprint(sketch[i]);
+                for j in 0..bucket.len() {
+                    if bucket_pos[j].to_usize() == i {
+                        result[bucket[j].to_usize()] += weights[i];
+                    }
+                }
+            }
         }
 
-        println!("------------------SKETCH END--------------------");
+        Ok(QueryResult::new(result))
     }
 
+    /// Genomes sharing positions with `sketch`, by Gid. Same counts as
+    /// query_sketch, but the cost only depends on the postings met: `counts`
+    /// is scratch space of zeros, grown to the number of genomes if needed and
+    /// left zeroed, to be reused from one query to the next.
+    pub fn query_sketch_sparse(&self, sketch: &Sketch, counts: &mut Vec<u32>) -> Result<Vec<Hit>> {
+        self.check_sketch(sketch)?;
+        counts.resize(counts.len().max(self.genome_numbers), 0);
+        let mut touched = Vec::new();
 
-    pub fn Biogetline(file_path: &str, result: &mut String, header: &mut String) {
-        let file = File::open(file_path).expect("Error opening file");
-        let mut reader = BufReader::new(file);
-        result.clear();
-
-        let first_char = reader
-            .fill_buf()
-            .expect("Error reading buffer")
-            .first()
-            .cloned()
-            .unwrap_or(0 as u8) as char;
+        for (i, val) in sketch.bins().iter().enumerate() {
+            if *val < self.fingerprint_range {
+                let bucket = &self.buckets[*val as usize];
+                let bucket_pos = &self.buckets_pos[*val as usize];
 
-        match first_char {
-            'Q' => {
-                reader.read_line(header).expect("Error reading line");
-                reader.read_line(result).expect("Error reading line");
-                reader.read_line(&mut String::new()).expect("Error reading line");
-                reader.read_line(&mut String::new()).expect("Error reading line");
-            }
-            'A' => {
-                let mut discard = String::new();
-                reader.read_line(&mut discard).expect("Error reading line");
-                let mut c = reader
-                    .fill_buf()
-                    .expect("Error reading buffer")
-                    .first()
-                    .cloned()
-                    .unwrap_or(0 as u8) as char;
-
-                while c != '>' && c != std::char::REPLACEMENT_CHARACTER {
-                    reader.read_line(&mut discard).expect("Error reading line");
-                    result.push_str(&discard);
-                    c = reader
-                        .fill_buf()
-                        .expect("Error reading buffer")
-                        .first()
-                        .cloned()
-                        .unwrap_or(0 as u8) as char;
+                for j in 0..bucket.len() {
+                    if bucket_pos[j].to_usize() == i {
+                        let gid = bucket[j].to_usize();
+                        if counts[gid] == 0 {
+                            touched.push(gid);
+                        }
+                        counts[gid] += 1;
+                    }
                 }
             }
-            _ => {}
-        }
-
-        if result.len() < 31 {
-            result.clear();
-            header.clear();
         }
 
+        touched.sort_unstable();
+        Ok(touched
+            .into_iter()
+            .map(|gid| Hit {
+                gid,
+                count: std::mem::take(&mut counts[gid]),
+            })
+            .collect())
     }
 
 
-    pub fn rcb(&self, mut min: u64) -> u64 {
-        let mut res = 0;
-        let mut offset = 1;
-        offset <<= 2 * self.k - 2;
-        for _ in 0..self.k {
-            res += (3 - (min % 4)) * offset;
-            min >>= 2;
-            offset >>= 2;
-        }
-        res
-    }
 
-    pub fn nuc2int(&self, c: char) -> u64 {
-        match c {
-            'C' => 1,
-            'G' => 2,
-            'T' => 3,
-            _ => 0,
+    pub fn header(&self) -> Header {
+        let Params { k, lf, w, e } = self.params();
+        Header {
+            k,
+            lf,
+            w,
+            e,
+            min_score: self.min_score,
+            shard: self.shard,
+            nb_shards: self.nb_shards,
+            id_bytes: G::BYTES,
+            pos_bytes: P::BYTES,
+            genome_numbers: self.genome_numbers as u64,
         }
     }
 
-    pub fn nuc2intrc(&self, c: char) -> u64 {
-        match c {
-            'A' => 3,
-            'C' => 2,
-            'G' => 1,
-            _ => 0,
+    /// Write the index to `path`, it can be read back with load
+    pub fn dump(&self, path: &Path) -> Result<()> {
+        let mut writer = Checksummed::new(BufWriter::new(File::create(path)?));
+        storage::write_header(&mut writer, &self.header())?;
+        storage::write_checksum(&mut writer)?;
+        for (gids, positions) in self.buckets.iter().zip(self.buckets_pos.iter()) {
+            storage::write_bucket(&mut writer, gids, positions)?;
         }
-    }
-
-    pub fn str2numstrand(&self, str: &str) -> u64 {
-        let mut res = 0;
-        for c in str.chars() {
-            res <<= 2;
-            res += match c {
-                'A' | 'a' => 0,
-                'C' | 'c' => 1,
-                'G' | 'g' => 2,
-                'T' | 't' => 3,
-                _ => return 0,
-            };
+        storage::write_checksum(&mut writer)?;
+        self.sketches.write(&mut writer)?;
+        storage::write_checksum(&mut writer)?;
+        for gid in 0..self.genome_numbers {
+            match self.metadata.get(gid) {
+                Some(metadata) => metadata.write(&mut writer)?,
+                None => GenomeMetadata::default().write(&mut writer)?,
+            }
         }
-        res
-    }
-
-    pub fn revhash64(&self, mut x: u64) -> u64 {
-        x = ((x >> 32) ^ x).wrapping_mul(0xD6E8FEB86659FD93);
-        x = ((x >> 32) ^ x).wrapping_mul(0xD6E8FEB86659FD93);
-        (x >> 32) ^ x
+        storage::write_checksum(&mut writer)?;
+        writer.flush()?;
+        Ok(())
     }
 
-    pub fn unrevhash64(&self, mut x: u64) -> u64 {
-        x = ((x >> 32) ^ x).wrapping_mul(0xCFEE444D8B59A89B);
-        x = ((x >> 32) ^ x).wrapping_mul(0xCFEE444D8B59A89B);
-        (x >> 32) ^ x
+    /// Load an index written by dump. The id and position widths of the file
+    /// must be G and P, see peek_header.
+    pub fn load(path: &Path) -> Result<Index<G, P>> {
+        let mut reader = storage::open_index(path)?;
+        let header = storage::read_header(&mut reader)?;
+        storage::check_checksum(&mut reader, "header")?;
+        if (header.id_bytes, header.pos_bytes) != (G::BYTES, P::BYTES) {
+            return Err(Error::InvalidParameters(format!(
+                "the index stores {}-bit ids and {}-bit positions, not {}-bit and {}-bit",
+                header.id_bytes * 8,
+                header.pos_bytes * 8,
+                G::BYTES * 8,
+                P::BYTES * 8
+            )));
+        }
+        let mut index = Index::new(Params {
+            k: header.k,
+            lf: header.lf,
+            w: header.w,
+            e: header.e,
+        })?;
+        index.min_score = header.min_score;
+        index.set_shard(header.shard, header.nb_shards)?;
+        for fingerprint in 0..index.fingerprint_range as usize {
+            let (gids, positions) = storage::read_bucket(&mut reader)?;
+            index.buckets[fingerprint] = gids;
+            index.buckets_pos[fingerprint] = positions;
+        }
+        storage::check_checksum(&mut reader, "buckets")?;
+        let bins = index.get_position_range().len();
+        index.sketches = SketchStore::read(&mut reader, header.w, bins, header.genome_numbers)?;
+        storage::check_checksum(&mut reader, "sketches")?;
+        // SketchStore::read checked the number of genomes against the file size
+        index.genome_numbers = header.genome_numbers as usize;
+        index.metadata = storage::read_items(&mut reader, index.genome_numbers, GenomeMetadata::read)?;
+        storage::check_checksum(&mut reader, "metadata")?;
+        Ok(index)
     }
 
-    pub fn update_kmer(&self, min: &mut u64, nuc: char) {
-        *min <<= 2;
-        *min += self.nuc2int(nuc);
-        *min %= self.offset_update_kmer;
+    /// Estimator for the counts of this index, on the positions it holds
+    pub fn estimator(&self) -> Estimator {
+        Estimator::new(self.params(), self.get_position_range().len())
     }
 
-    pub fn update_kmer_RC(&self, min: &mut u64, nuc: char) {
-        *min >>= 2;
-        *min += self.nuc2intrc(nuc) << (2 * self.k - 2);
+    // Every stored sketch queried through the index, in parallel
+    fn query_all(&self, weights: Option<&[u32]>) -> Result<Vec<QueryResult>> {
+        (0..self.genome_numbers)
+            .into_par_iter()
+            .map(|gid| {
+                let sketch = self.stored_sketch(gid);
+                match weights {
+                    Some(weights) => self.query_sketch_weighted(&sketch, weights),
+                    None => self.query_sketch(&sketch),
+                }
+            })
+            .collect()
     }
 
-    pub fn hash_family(&self, x: u64, factor: u64) -> u64 {
-        self.unrevhash64(x) + factor * self.revhash64(x)
-    }
-    pub fn get_perfect_fingerprint(&self, hashed: u64) -> u64 {
-        let b = hashed;
-        let mut frac = (1u64 << 63 - b) as f64 / (1u64 << 63) as f64;
-        frac = frac.powf(self.e as f64 / self.f as f64);
-        frac = 1.0 - frac;
-        (self.fingerprint_range as f64 * frac) as u64
+    /// Symmetric matrix of the Mash distances between every pair of genomes.
+    /// With `weights`, matches are counted as by query_sketch_weighted.
+    pub fn distance_matrix(&self, estimator: &Estimator, weights: Option<&[u32]>) -> Result<Vec<Vec<f64>>> {
+        let counts = self.query_all(weights)?;
+        let size = self.genome_numbers;
+        let mut matrix = vec![vec![0.0; size]; size];
+        for (i, row) in counts.iter().enumerate() {
+            for (j, &shared) in row.counts().iter().enumerate().skip(i + 1) {
+                let similarity = estimator.estimate(shared, self.metadata[i].cardinality, self.metadata[j].cardinality);
+                matrix[i][j] = similarity.distance;
+                matrix[j][i] = similarity.distance;
+            }
+        }
+        Ok(matrix)
     }
 
+    /// Compare every pair of genomes by querying their stored sketches
+    /// through the index. `values` picks what the matrix holds: symmetric
+    /// distances are written once per pair, the containment for every
+    /// ordered pair. Pairs go to `writer` row by row, the matrix at the end.
+    pub fn write_distances(&self, estimator: &Estimator, values: DistanceMatrix, writer: &mut dyn ResultWriter) -> Result<()> {
+        let size = self.genome_numbers;
+        let counts = self.query_all(None)?;
+        let symmetric = values.is_symmetric();
 
-    pub fn sketch_densification(&self, sketch: &mut Vec<u64>, empty_cell: u32) {
-        let size = sketch.len();
-        let empty_cell = Arc::new(Mutex::new(empty_cell));
-        let mi = u64::MAX;
-
-        while *empty_cell.lock().unwrap() != 0 {
-            for i in 0..size {
-                if sketch[i] != mi {
-                    let hash = self.hash_family(sketch[i], mi.into()) % self.f as u64;
-                    let mut empty_cell_guard = empty_cell.lock().unwrap();
-                    if *empty_cell_guard > 0 && sketch[hash as usize] == mi {
-                        let temp = std::mem::replace(&mut sketch[i], 0);
-                        let _ = std::mem::replace(&mut sketch[hash as usize], temp);
-                        sketch[hash as usize] = temp;
-                        *empty_cell_guard -= 1;
-                    }
+        let mut matrix = vec![vec![0.0; size]; size];
+        for (i, row) in counts.iter().enumerate() {
+            let query_cardinality = self.metadata[i].cardinality;
+            for (j, &shared) in row.counts().iter().enumerate() {
+                if i == j {
+                    matrix[i][j] = if symmetric { 0.0 } else { 1.0 };
+                    continue;
+                }
+                if symmetric && j < i {
+                    continue;
+                }
+                let similarity = estimator.estimate(shared, query_cardinality, self.metadata[j].cardinality);
+                writer.write_pair(&self.metadata[i], &self.metadata[j], &similarity)?;
+                let value = match values {
+                    DistanceMatrix::Containment => similarity.containment,
+                    DistanceMatrix::Jaccard => 1.0 - similarity.jaccard,
+                    DistanceMatrix::Mash => similarity.distance,
+                };
+                matrix[i][j] = value;
+                if symmetric {
+                    matrix[j][i] = value;
                 }
             }
         }
+
+        let genomes: Vec<&GenomeMetadata> = self.metadata[..size].iter().collect();
+        writer.write_matrix(&genomes, &matrix)?;
+        Ok(())
     }
 
-    pub fn query_sketch(&self, sketch: &[u64]) -> Vec<u32> {
-        let genome_numbers = self.genome_numbers.lock().unwrap();
-        let mut result = vec![0; *genome_numbers as usize];
+    // Stored sketches of `gids` queried through the buckets in parallel,
+    // with for each one the genomes `keep` accepts
+    fn sparse_neighbors<F>(&self, gids: &[Gid], estimator: &Estimator, keep: F) -> Result<Vec<Vec<(Gid, Similarity)>>>
+    where
+        F: Fn(Gid, Gid, &Similarity) -> bool + Sync,
+    {
+        gids.par_iter()
+            .map_init(Vec::new, |counts, &i| {
+                let hits = self.query_sketch_sparse(&self.stored_sketch(i), counts)?;
+                let query_cardinality = self.metadata[i].cardinality;
+                Ok(hits
+                    .into_iter()
+                    .map(|hit| {
+                        let genome_cardinality = self.metadata[hit.gid].cardinality;
+                        (hit.gid, estimator.estimate(hit.count, query_cardinality, genome_cardinality))
+                    })
+                    .filter(|(j, similarity)| keep(i, *j, similarity))
+                    .collect())
+            })
+            .collect()
+    }
 
-        for (i, val) in sketch.iter().enumerate() {
-            if *val < self.fingerprint_range {
-                let bucket = &self.buckets[*val as usize];
-                let bucket_pos = &self.buckets_pos[*val as usize];
+    /// Other genomes accepted by `keep`, for every genome, found through the
+    /// buckets like write_sparse_distances
+    pub fn neighbors<F>(&self, estimator: &Estimator, keep: F) -> Result<Vec<Vec<(Gid, Similarity)>>>
+    where
+        F: Fn(&Similarity) -> bool + Sync,
+    {
+        let gids: Vec<Gid> = (0..self.genome_numbers).collect();
+        self.sparse_neighbors(&gids, estimator, |i, j, similarity| i != j && keep(similarity))
+    }
 
-                for j in 0..bucket.len() {
-                    if bucket_pos[j] == i as u16 {
-                        result[bucket[j] as usize] += 1;
-                    }
+    /// The `k` other genomes sharing the most positions with each genome, at
+    /// least the minimum score, best first. Every stored sketch is queried
+    /// through query_sketch_sparse in parallel, with scratch counts for each
+    /// thread, and only the kept neighbours are held.
+    pub fn nearest_neighbors(&self, estimator: &Estimator, k: usize) -> Result<Vec<Vec<(Gid, Similarity)>>> {
+        let min_score = self.min_score.max(1);
+        (0..self.genome_numbers)
+            .into_par_iter()
+            .map_init(Vec::new, |counts, i| {
+                let hits = self.query_sketch_sparse(&self.stored_sketch(i), counts)?;
+                let hits = hits.into_iter().filter(|hit| hit.gid != i && hit.count >= min_score).collect();
+                let query_cardinality = self.metadata[i].cardinality;
+                Ok(query::rank(hits, Some(k))
+                    .into_iter()
+                    .map(|hit| {
+                        let genome_cardinality = self.metadata[hit.gid].cardinality;
+                        (hit.gid, estimator.estimate(hit.count, query_cardinality, genome_cardinality))
+                    })
+                    .collect())
+            })
+            .collect()
+    }
+
+    /// All against all comparison for large collections: every stored sketch
+    /// is queried through the buckets, so pairs of genomes without any shared
+    /// position are never looked at. Each pair is compared once, the smaller
+    /// Gid as the query, and written to `writer` when `keep` accepts it.
+    /// Queries run in parallel batches, written in Gid order.
+    pub fn write_sparse_distances<F>(&self, estimator: &Estimator, keep: F, writer: &mut dyn ResultWriter) -> Result<()>
+    where
+        F: Fn(&Similarity) -> bool + Sync,
+    {
+        let batch_size = rayon::current_num_threads() * 64;
+        let gids: Vec<Gid> = (0..self.genome_numbers).collect();
+        for batch in gids.chunks(batch_size) {
+            let edges = self.sparse_neighbors(batch, estimator, |i, j, similarity| j > i && keep(similarity))?;
+            for (&i, edges) in batch.iter().zip(edges) {
+                for (j, similarity) in edges {
+                    writer.write_pair(&self.metadata[i], &self.metadata[j], &similarity)?;
                 }
             }
         }
-
-        result
+        Ok(())
     }
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    const PARAMS: Params = Params { k: 15, lf: 8, w: 8, e: 1000 };
 
-    pub fn merge_sketch(&self, sketch1: &mut [i32], sketch2: &[i32]) {
-        for i in 0..sketch1.len() {
-            sketch1[i] = sketch1[i].min(sketch2[i]);
+    fn sketch(seed: u64) -> Sketch {
+        Sketch::from_bins((0..256u64).map(|i| (i * 7 + seed * 31) % 256).collect())
+    }
+
+    #[test]
+    fn insert_genome_assigns_the_next_id() {
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        for gid in 0..3 {
+            let metadata = GenomeMetadata::new(&format!("g{}", gid), &[], 0);
+            assert_eq!(index.insert_genome(&sketch(gid as u64), metadata).unwrap(), gid);
         }
+        let short = Sketch::from_bins(vec![0; 10]);
+        assert!(matches!(
+            index.insert_genome(&short, GenomeMetadata::default()),
+            Err(Error::SketchSize { .. })
+        ));
+        assert_eq!(index.get_nb_genomes(), 3);
+        assert_eq!(index.get_name(2), Some("g2"));
+        assert_eq!(index.get_name(3), None);
+        assert_eq!(index.query_sketch(&sketch(1)).unwrap().counts()[1], 256);
     }
-    pub fn print_matrix(&self) {
-        println!("PRINT MATRIX: ");
-        let size = self.genome_numbers.lock().unwrap().clone() as usize;
-        let mut matrix = vec![vec![0.0; size]; size];
 
-        for i in 0..size {
-            println!("i:'{}' new", i);
-            let filenames_lock = self.filenames.lock().unwrap();
-            if i < filenames_lock.len() {
-                let filename_i = &filenames_lock[i];
-                let file_sketches = self.file_sketches.lock().unwrap();
-                let sketch_i = match file_sketches.get(filename_i) {
-                    Some(sketch) => sketch,
-                    None => {
-                        eprintln!("Could not find sketch for filename '{}'", filename_i);
-                        continue;
-                    }
-                };
+    // Genome g keeps the bins of genome 0 on a fraction of the positions
+    // decreasing with g, and has bins of its own elsewhere
+    fn related_sketch(g: u64) -> Sketch {
+        Sketch::from_bins(
+            (0..256u64)
+                .map(|i| if (i * 37 + g * 11) % 9 >= g { (i * 7) % 256 } else { (i * 7 + g * 53) % 256 })
+                .collect(),
+        )
+    }
 
-                for j in i..size {
-                    println!("j:'{}' new", j);
-                    if j < filenames_lock.len() {
-                        if i == j {
-                            matrix[i][j] = 0.0;
-                        } else {
-                            let filename_j = &filenames_lock[j];
-                            let sketches = self.file_sketches.lock().unwrap();
-                            let sketch_j = match sketches.get(filename_j) {
-                                Some(sketch) => sketch,
-                                None => {
-                                    eprintln!("Could not find sketch for filename '{}'", filename_j);
-                                    continue;
-                                }
-                            };
-
-                            let intersection: Vec<&u64> = sketch_i
-                                .iter()
-                                .filter(|sketch| sketch_j.contains(sketch))
-                                .collect();
-
-                            let mut union_set: HashSet<u64> = HashSet::new();
-                            union_set.extend(sketch_i);
-                            union_set.extend(sketch_j);
-
-                            let jaccard_distance =
-                                1.0 - (intersection.len() as f64) / (union_set.len() as f64);
-
-                            matrix[i][j] = jaccard_distance;
-                            matrix[j][i] = jaccard_distance;
-                        }
-                    }
-                }
+    #[test]
+    fn nearest_neighbors_match_the_dense_queries() {
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        for g in 0..8 {
+            index.insert_genome(&related_sketch(g), GenomeMetadata::new(&format!("g{}", g), &[], 1000)).unwrap();
+        }
+        index.set_min_score(30);
+        let estimator = index.estimator();
+        for k in [0, 1, 3, 7, 20] {
+            let neighbors = index.nearest_neighbors(&estimator, k).unwrap();
+            assert_eq!(neighbors.len(), 8);
+            for (i, edges) in neighbors.iter().enumerate() {
+                let expected: Vec<(Gid, u32)> = index
+                    .query_sketch(&index.get_sketch(i).unwrap())
+                    .unwrap()
+                    .ranked(30, None)
+                    .into_iter()
+                    .filter(|hit| hit.gid != i)
+                    .take(k)
+                    .map(|hit| (hit.gid, hit.count))
+                    .collect();
+                let found: Vec<(Gid, u32)> = edges.iter().map(|(gid, similarity)| (*gid, similarity.shared)).collect();
+                assert_eq!(found, expected, "genome {} with k = {}", i, k);
+                assert!(edges.len() <= k);
             }
         }
+        assert!(index.nearest_neighbors(&estimator, 3).unwrap().iter().all(|edges| edges.len() == 3));
+    }
+
+    #[test]
+    fn positions_must_fit_their_width() {
+        let large = Params { lf: 17, ..PARAMS };
+        assert!(matches!(Index::<u32, u16>::new(large), Err(Error::InvalidParameters(_))));
+        assert!(Index::<u32, u32>::new(large).is_ok());
+    }
 
-        print!("##Names ");
-        let filenames_guard = self.filenames.lock().unwrap();
-        for filename in &*filenames_guard {
-            print!("{}\t", filename);
+    #[test]
+    fn u16_ids_fill_up() {
+        let mut index: Index<u16, u16> = Index::new(Params { lf: 1, ..PARAMS }).unwrap();
+        let sketch = Sketch::from_bins(vec![3, 5]);
+        for gid in 0..=u16::MAX as usize {
+            assert_eq!(index.insert_genome(&sketch, GenomeMetadata::default()).unwrap(), gid);
         }
-        println!();
-
-        for i in 0..size {
-            let filenames_guard = self.filenames.lock().unwrap();
-            if i < filenames_guard.len() {
-                print!("{}\t", filenames_guard[i]);
-                for j in 0..size {
-                    if j < filenames_guard.len() {
-                        if i == j {
-                            print!("-\t");
-                        } else {
-                            print!("{:.3}\t", matrix[i][j]);
-                        }
-                    }
-                }
-                println!();
-            }
+        match index.insert_genome(&sketch, GenomeMetadata::default()) {
+            Err(Error::IndexFull { id_bits, max_genomes }) => assert_eq!((id_bits, max_genomes), (16, 65536)),
+            _ => panic!("a 65537th genome fits in u16 ids"),
         }
+        assert_eq!(index.get_nb_genomes(), 65536);
+    }
 
+    #[test]
+    fn u16_index_survives_dump_and_load() {
+        let path = std::env::temp_dir().join(format!("onika-index-u16-{}.idx", std::process::id()));
+        let mut index: Index<u16, u16> = Index::new(PARAMS).unwrap();
+        for g in 0..8 {
+            index.insert_genome(&related_sketch(g), GenomeMetadata::new(&format!("g{}", g), &[], 1000)).unwrap();
+        }
+        index.set_min_score(5);
+        index.dump(&path).unwrap();
+        let header = storage::peek_header(&path).unwrap();
+        assert_eq!((header.id_bytes, header.pos_bytes), (2, 2));
+
+        let loaded: Index<u16, u16> = Index::load(&path).unwrap();
+        assert_eq!(loaded.get_nb_genomes(), 8);
+        assert_eq!(loaded.get_min_score(), 5);
+        for g in 0..8 {
+            assert_eq!(loaded.get_name(g as usize), index.get_name(g as usize));
+            assert_eq!(loaded.get_sketch(g as usize).unwrap().bins(), related_sketch(g).bins());
+            assert_eq!(loaded.query_sketch(&related_sketch(g)).unwrap(), index.query_sketch(&related_sketch(g)).unwrap());
+        }
+        assert!(Index::<u32, u16>::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
     }
 }
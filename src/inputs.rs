@@ -0,0 +1,192 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::duplicates::{Duplicate, DuplicatePolicy};
+use crate::error::{Error, Result};
+use crate::index::Gid;
+use crate::novelty::Novelty;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    Missing,        // no such file
+    Unreadable,     // the file cannot be opened or read
+    Empty,          // no sequence or no base at all
+    TooShort,       // no sequence of at least k valid bases, so no k-mer
+}
+
+impl IssueKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IssueKind::Missing => "missing",
+            IssueKind::Unreadable => "unreadable",
+            IssueKind::Empty => "empty",
+            IssueKind::TooShort => "too_short",
+        }
+    }
+}
+
+/// An input of the file of files that was not indexed, `line` counts from 1
+pub struct InputIssue {
+    pub line: usize,
+    pub path: String,
+    pub kind: IssueKind,
+    pub message: String,
+}
+
+impl InputIssue {
+    pub fn description(&self) -> String {
+        format!("line {}: '{}' is {} ({})", self.line, self.path, self.kind.name(), self.message)
+    }
+}
+
+/// What happens to the inputs of a build, handed to the caller as it goes:
+/// the library itself prints nothing
+pub enum BuildEvent<'a> {
+    Adding(&'a str),                           // genome sketched, about to be inserted
+    Added(&'a str, Gid),
+    Skipped(&'a InputIssue),                   // input left out, see InputReport
+    Duplicate(&'a Duplicate, DuplicatePolicy), // see DuplicateFinder
+    Novelty(&'a Novelty),                      // see NoveltyReport
+    IgnoredTag(&'a str, &'a str),              // (path, field) of a tag without '='
+}
+
+/// Inputs left out of a build. In strict mode the first one stops the build.
+pub struct InputReport {
+    strict: bool,
+    issues: Vec<InputIssue>,
+}
+
+impl InputReport {
+    pub fn new(strict: bool) -> InputReport {
+        InputReport {
+            strict,
+            issues: Vec::new(),
+        }
+    }
+
+    /// Record an input left out, an error in strict mode
+    pub fn add(&mut self, issue: InputIssue) -> Result<&InputIssue> {
+        if self.strict {
+            let description = issue.description();
+            self.issues.push(issue);
+            return Err(Error::RejectedInput(description));
+        }
+        self.issues.push(issue);
+        Ok(&self.issues[self.issues.len() - 1])
+    }
+
+    /// Inputs left out so far, in input order
+    pub fn issues(&self) -> &[InputIssue] {
+        &self.issues
+    }
+
+    /// Tab separated: line, path, problem, details
+    pub fn write_tsv(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "line\tpath\tproblem\tdetails")?;
+        for issue in &self.issues {
+            writeln!(writer, "{}\t{}\t{}\t{}", issue.line, issue.path, issue.kind.name(), issue.message)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Number of inputs skipped, by problem
+impl fmt::Display for InputReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kinds = [
+            IssueKind::Missing,
+            IssueKind::Unreadable,
+            IssueKind::Empty,
+            IssueKind::TooShort,
+        ];
+        let counts: Vec<String> = kinds
+            .iter()
+            .map(|&kind| (kind, self.issues.iter().filter(|issue| issue.kind == kind).count()))
+            .filter(|&(_, count)| count > 0)
+            .map(|(kind, count)| format!("{} {}", count, kind.name()))
+            .collect();
+        write!(f, "{} inputs skipped: {}", self.issues.len(), counts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketcher::{Params, Sketcher};
+    use std::fs;
+
+    // One file per kind of bad input, a good genome last; the directory
+    // itself stands for an unreadable file
+    fn inputs(dir: &Path) -> Vec<String> {
+        fs::create_dir_all(dir).unwrap();
+        let files = [("empty.fasta", ">a\n>b\n"), ("short.fasta", ">a\nACGTACGT\n"), ("good.fasta", ">a\nACGTTGCAACGTAGGCTAGCTAGGATCGA\n")];
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        [dir.join("missing.fasta"), dir.to_path_buf(), dir.join("empty.fasta"), dir.join("short.fasta"), dir.join("good.fasta")]
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect()
+    }
+
+    fn issue(line: usize, path: &str, kind: IssueKind, message: String) -> InputIssue {
+        InputIssue {
+            line,
+            path: path.to_string(),
+            kind,
+            message,
+        }
+    }
+
+    #[test]
+    fn bad_inputs_are_classified() {
+        let dir = std::env::temp_dir().join(format!("onika-inputs-{}", std::process::id()));
+        let sketcher = Sketcher::new(Params { k: 15, lf: 8, w: 8, e: 1000 }).unwrap();
+        let kinds: Vec<&str> = inputs(&dir)
+            .iter()
+            .map(|path| match sketcher.sketch_input(path) {
+                Ok(_) => "ok",
+                Err((kind, _)) => kind.name(),
+            })
+            .collect();
+        assert_eq!(kinds, ["missing", "unreadable", "empty", "too_short", "ok"]);
+        match sketcher.sketch_input(&dir.join("short.fasta").display().to_string()) {
+            Err((_, message)) => assert_eq!(message, "no 15-mer in 8 bases"),
+            Ok(_) => panic!("a genome shorter than k sketched"),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn report_counts_and_lists_the_issues() {
+        let mut report = InputReport::new(false);
+        let kinds = [IssueKind::Missing, IssueKind::Empty, IssueKind::Missing, IssueKind::TooShort];
+        for (line, &kind) in kinds.iter().enumerate() {
+            let added = report.add(issue(line + 1, &format!("g{}", line), kind, String::from("details"))).unwrap();
+            assert_eq!(added.line, line + 1);
+        }
+        assert_eq!(report.issues().len(), 4);
+        assert_eq!(report.to_string(), "4 inputs skipped: 2 missing, 1 empty, 1 too_short");
+        assert_eq!(report.issues()[1].description(), "line 2: 'g1' is empty (details)");
+
+        let path = std::env::temp_dir().join(format!("onika-inputs-{}.tsv", std::process::id()));
+        report.write_tsv(&path).unwrap();
+        let tsv = fs::read_to_string(&path).unwrap();
+        assert_eq!(tsv.lines().next(), Some("line\tpath\tproblem\tdetails"));
+        assert_eq!(tsv.lines().nth(4), Some("4\tg3\ttoo_short\tdetails"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn strict_report_rejects_the_first_issue() {
+        let mut report = InputReport::new(true);
+        match report.add(issue(3, "g.fasta", IssueKind::Unreadable, String::from("denied"))) {
+            Err(Error::RejectedInput(message)) => assert_eq!(message, "line 3: 'g.fasta' is unreadable (denied)"),
+            _ => panic!("strict report accepted an issue"),
+        }
+        assert_eq!(report.issues().len(), 1);
+    }
+}
@@ -0,0 +1,55 @@
+//! Genome similarity search with Onika style sketches.
+//!
+//! A [`Sketcher`] turns a genome into a [`Sketch`]: one w-bit fingerprint per
+//! bin of a One Permutation Hashing sketch. An [`Index`] stores, for each
+//! fingerprint, the genomes and sketch positions holding it, so that a query
+//! sketch is compared to every indexed genome by reading one bucket per
+//! position. The number of positions a query shares with each genome comes
+//! back as a [`QueryResult`].
+//!
+//! ```no_run
+//! use rustic_onika::{Index, Params};
+//!
+//! # fn main() -> rustic_onika::Result<()> {
+//! let mut index: Index = Index::new(Params::default())?;
+//! let (sketch, metadata) = index.sketcher().sketch_genome("genome1.fasta")?;
+//! let gid = index.insert_genome(&sketch, metadata)?;
+//! assert_eq!(gid, 0);
+//!
+//! let query = index.sketcher().sketch_file("query.fasta")?;
+//! for hit in index.query_sketch(&query)?.hits() {
+//!     println!("{}\t{}", index.get_all_metadata()[hit.gid].name, hit.count);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod cluster;
+pub mod duplicates;
+pub mod error;
+pub mod external;
+pub mod ffi;
+pub mod graph;
+pub mod index;
+pub mod inputs;
+pub mod metadata;
+pub mod novelty;
+pub mod output;
+pub mod query;
+pub mod shard;
+pub mod similarity;
+mod sketch_store;
+pub mod sketcher;
+pub mod stats;
+pub mod tree;
+mod storage;
+pub mod verify;
+pub mod width;
+
+pub use error::{Error, Result};
+pub use index::{DistanceMatrix, Gid, Index};
+pub use metadata::GenomeMetadata;
+pub use query::{Hit, QueryResult};
+pub use similarity::{Estimator, Similarity};
+pub use sketcher::{Params, Sketch, Sketcher};
+pub use storage::{peek_header, Header};
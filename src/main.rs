@@ -1,10 +1,63 @@
-mod index;
-
 use std::process::exit;
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
 use structopt::StructOpt;
-use index::Index; 
+use rustic_onika::cluster::{self, ClusterMethod, Clustering};
+use rustic_onika::duplicates::DuplicatePolicy;
+use rustic_onika::external::ExternalBuilder;
+use rustic_onika::graph::{self, GraphFormat};
+use rustic_onika::inputs::{BuildEvent, InputIssue, InputReport, IssueKind};
+use rustic_onika::metadata::GenomeMetadata;
+use rustic_onika::novelty::NoveltyReport;
+use rustic_onika::output::{self, Format, ResultWriter};
+use rustic_onika::shard::ShardSet;
+use rustic_onika::stats::IndexStats;
+use rustic_onika::tree::{resample_positions, Tree, TreeMethod};
+use rustic_onika::width::{self, IndexInt, WidthVisitor};
+use rustic_onika::{verify, DistanceMatrix, Estimator, Gid, Index, Params, QueryResult, Similarity, Sketch};
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(about = "Report bucket occupancy, posting list lengths and memory use of the index.")]
+    Info {
+        #[structopt(long = "json", help = "Print the report as JSON.")]
+        json: bool,
+    },
+    #[structopt(about = "Build a tree from the Mash distances between the indexed genomes and write it in Newick.")]
+    Tree {
+        #[structopt(long = "method", default_value = "nj", help = "Tree building method: nj (neighbor joining) or upgma.")]
+        method: TreeMethod,
+        #[structopt(long = "bootstrap", default_value = "0", help = "Number of bootstrap replicates, resampling the sketch positions. Supports are written as node labels.")]
+        bootstrap: usize,
+        #[structopt(long = "seed", default_value = "1", help = "Seed of the bootstrap resampling.")]
+        seed: u64,
+    },
+    #[structopt(about = "Group the indexed genomes closer than an ANI threshold and pick a representative per cluster.")]
+    Cluster {
+        #[structopt(long = "method", default_value = "greedy", help = "Clustering method: single (single linkage) or greedy (greedy centroids, each genome within the threshold of its representative).")]
+        method: ClusterMethod,
+        #[structopt(long = "min-ani", default_value = "0.95", help = "ANI threshold between genomes of a cluster.")]
+        min_ani: f64,
+        #[structopt(long = "quality-tag", help = "Pick as representative the genome with the highest value of this tag of the file of files, instead of the largest assembly.")]
+        quality_tag: Option<String>,
+        #[structopt(long = "representatives", parse(from_os_str), help = "Write the names of the representatives to this file, one per line.")]
+        representatives: Option<PathBuf>,
+    },
+    #[structopt(about = "Link every indexed genome to its k most similar other genomes and write the graph.")]
+    Knn {
+        #[structopt(short = "k", long = "neighbors", default_value = "10", help = "Number of neighbours of each genome.")]
+        k: usize,
+        #[structopt(long = "graph-format", default_value = "tsv", help = "Graph format: tsv (edge list), graphml or gexf. Edges are weighted by the Jaccard estimate.")]
+        format: GraphFormat,
+    },
+    #[structopt(about = "Check the checksums and the consistency of index files.")]
+    Verify {
+        #[structopt(parse(from_os_str), help = "Index files to check.")]
+        files: Vec<PathBuf>,
+    },
+}
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "rustic-onika", about = "Description du programme")]
@@ -53,12 +106,168 @@ struct Options {
     egs: Option<i32>,
 
 
+    #[structopt(
+        long = "min-score",
+        help = "Only report genomes sharing at least this many sketch positions with a query. Saved with a built index, loaded indexes default to their saved value (1)."
+    )]
+    min_score: Option<u32>,
+
+    #[structopt(
+        long = "top",
+        help = "Only report the N best genomes of each query."
+    )]
+    top: Option<usize>,
+
+    #[structopt(
+        long = "max-pvalue",
+        help = "Only report genomes whose shared positions have at most this probability to occur by chance (1)."
+    )]
+    max_pvalue: Option<f64>,
+
+    #[structopt(
+        long = "confidence",
+        default_value = "0.95",
+        help = "Confidence level of the intervals given for the Jaccard, distance and ANI estimates."
+    )]
+    confidence: f64,
+
+    #[structopt(
+        long = "format",
+        default_value = "tsv",
+        help = "Layout of query and --dist results: tsv, csv or jsonl (one pair per line), phylip (--dist --symmetric or --mash only) or matrix (--dist only)."
+    )]
+    format: Format,
+
+    #[structopt(
+        long = "output",
+        parse(from_os_str),
+        help = "Write query and --dist results to this file instead of stdout."
+    )]
+    output: Option<PathBuf>,
+
     #[structopt(
         long = "dist",
-        help = "Generate a full, asymmetric distance matrix."
+        help = "Compare every pair of indexed genomes. Row i, column j of the matrix is the containment of genome i in genome j."
     )]
     dist: bool,
 
+    #[structopt(
+        long = "symmetric",
+        help = "With --dist, give the symmetric Jaccard distance 1 - J instead of the containment."
+    )]
+    symmetric: bool,
+
+    #[structopt(
+        long = "mash",
+        conflicts_with = "symmetric",
+        help = "With --dist, give the symmetric Mash distance from the Jaccard estimate instead of the containment."
+    )]
+    mash: bool,
+
+    #[structopt(
+        long = "sparse",
+        help = "With --dist, only compare the genomes sharing sketch positions through the index, and stream the pairs passing --min-ani, --min-score and --max-pvalue as an edge list instead of a matrix. Without --min-ani or --max-pvalue, pairs need a p-value of at most 0.001."
+    )]
+    sparse: bool,
+
+    #[structopt(
+        long = "min-ani",
+        help = "With --dist --sparse, only write the pairs with at least this ANI estimate (0)."
+    )]
+    min_ani: Option<f64>,
+
+    #[structopt(
+        short = "O",
+        long = "output-index",
+        help = "Write the index to this file."
+    )]
+    output_index: Option<PathBuf>,
+
+    #[structopt(
+        short = "L",
+        long = "load",
+        number_of_values = 1,
+        help = "Load an index previously written with -O. Give every shard file of a sharded index to query them together."
+    )]
+    load: Vec<PathBuf>,
+
+    #[structopt(
+        long = "shards",
+        help = "Partition the index by sketch position into this many shards (1). Requires --shard."
+    )]
+    shards: Option<u32>,
+
+    #[structopt(
+        long = "shard",
+        help = "Only build shard number j (from 0) of the --shards partition."
+    )]
+    shard: Option<u32>,
+
+    #[structopt(
+        short = "M",
+        long = "max-memory",
+        help = "Build the index out of core, keeping at most this many MB of postings in memory. Requires -O."
+    )]
+    max_memory: Option<usize>,
+
+    #[structopt(
+        long = "tmp-dir",
+        help = "Directory for the temporary runs of the out of core build (system temporary directory)."
+    )]
+    tmp_dir: Option<PathBuf>,
+
+    #[structopt(
+        long = "duplicates",
+        default_value = "skip",
+        help = "What to do with a genome already indexed, by path or by sketch: skip it, warn and index it again, or alias its path to the existing genome."
+    )]
+    duplicates: DuplicatePolicy,
+
+    #[structopt(
+        long = "strict",
+        conflicts_with = "skip-errors",
+        help = "Fail the build on the first missing, unreadable, empty or too short input."
+    )]
+    strict: bool,
+
+    #[structopt(
+        long = "skip-errors",
+        parse(from_os_str),
+        help = "Skip the missing, unreadable, empty or too short inputs and list them in this TSV file."
+    )]
+    skip_errors: Option<PathBuf>,
+
+    #[structopt(
+        long = "novelty",
+        help = "Query each genome against the index before adding it, and report its nearest indexed genome and whether it is novel."
+    )]
+    novelty: bool,
+
+    #[structopt(
+        long = "novelty-ani",
+        default_value = "0.95",
+        help = "With --novelty, genomes below this ANI to every indexed genome are novel."
+    )]
+    novelty_ani: f64,
+
+    #[structopt(
+        long = "novelty-report",
+        parse(from_os_str),
+        help = "Write the novelty of the added genomes to this TSV file. Implies --novelty."
+    )]
+    novelty_report: Option<PathBuf>,
+
+    #[structopt(
+        long = "id-width",
+        help = "Bits used to store a genome id: 16, 32 or 64 (32). Loaded indexes keep the width they were built with."
+    )]
+    id_width: Option<u32>,
+
+    #[structopt(
+        long = "pos-width",
+        help = "Bits used to store a sketch position: 16 or 32 (16). 16 bits limit S to 16."
+    )]
+    pos_width: Option<u32>,
 
     #[structopt(
         short = "h",
@@ -66,29 +275,599 @@ struct Options {
         help = "Print usage and exit."
     )]
     help: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() {
-    let opts: Options = Options::from_args();
-    let mut monindex = Index::new(10, 31, 8, 5000000, String::from("example.txt"));
-
-     if let Some(list_file) = opts.index {
-        if let Ok(file) = File::open(&list_file) {
-            let reader = io::BufReader::new(file);
-            for line in reader.lines() {
-                if let Ok(genome_file) = line {
-                    monindex.get_filename(&genome_file);
+// Metadata and hits of a query kept for output, or why its file was left out
+type QueryHits = Result<rustic_onika::Result<(GenomeMetadata, Vec<(Gid, Similarity)>)>, (IssueKind, String)>;
+
+// Queries are sketched and run in parallel, results are written in input order,
+// best genomes first. `index` sketches the queries and names the genomes,
+// `estimator` turns the counts of `query_sketch` into similarities.
+fn query_files<G: IndexInt, P: IndexInt, Q>(
+    query_file: &Path,
+    index: &Index<G, P>,
+    estimator: &Estimator,
+    opts: &Options,
+    writer: &mut dyn ResultWriter,
+    query_sketch: Q,
+) where
+    Q: Fn(&Sketch) -> rustic_onika::Result<QueryResult> + Sync,
+{
+    // Query paths with their line in the file, counting from 1
+    let queries: Vec<(usize, String)> = match File::open(query_file) {
+        Ok(file) => io::BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .enumerate()
+            .map(|(line_number, query)| (line_number + 1, query.trim().to_string()))
+            .filter(|(_, query)| !query.is_empty())
+            .collect(),
+        Err(_) => {
+            eprintln!("Unable to open the file '{}'", query_file.display());
+            exit(1);
+        }
+    };
+
+    let min_score = opts.min_score.unwrap_or_else(|| index.get_min_score());
+    let max_pvalue = opts.max_pvalue.unwrap_or(1.0);
+    // The p-value filter comes before --top, which then needs every hit
+    let top = if opts.max_pvalue.is_some() { None } else { opts.top };
+    // Only the hits passing the filters are kept while the queries run. Queries
+    // that cannot be sketched are left out like the inputs of a build.
+    let genomes = index.get_all_metadata();
+    let results: Vec<QueryHits> = queries
+        .par_iter()
+        .map(|(_, query)| {
+            let (sketch, metadata) = index.sketcher().sketch_input(query)?;
+            Ok(query_sketch(&sketch).map(|result| {
+                let hits = result
+                    .ranked(min_score, top)
+                    .into_iter()
+                    .map(|hit| (hit.gid, estimator.estimate(hit.count, metadata.cardinality, genomes[hit.gid].cardinality)))
+                    .filter(|(_, similarity)| similarity.pvalue <= max_pvalue)
+                    .take(opts.top.unwrap_or(usize::MAX))
+                    .collect();
+                (metadata, hits)
+            }))
+        })
+        .collect();
+
+    let mut inputs = InputReport::new(false);
+    for ((line, query), result) in queries.iter().zip(results) {
+        match result {
+            Err((kind, message)) => {
+                let issue = InputIssue {
+                    line: *line,
+                    path: query.clone(),
+                    kind,
+                    message,
+                };
+                if let Ok(issue) = inputs.add(issue) {
+                    eprintln!("Skipping query, {}", issue.description());
+                }
+            }
+            Ok(Ok((metadata, similarities))) => {
+                for (gid, similarity) in similarities {
+                    if let Err(e) = writer.write_pair(&metadata, &genomes[gid], &similarity) {
+                        eprintln!("Unable to write the results: {}", e);
+                        exit(1);
+                    }
                 }
             }
+            Ok(Err(e)) => eprintln!("Unable to query '{}': {}", query, e),
+        }
+    }
+    if !inputs.issues().is_empty() {
+        eprintln!("{}", inputs);
+    }
+}
+
+// Steps of a build, as the command line reports them. They go to stderr,
+// stdout only carries results.
+fn print_progress(event: BuildEvent) {
+    match event {
+        BuildEvent::Adding(path) => eprintln!("Adding file: '{}'", path),
+        BuildEvent::Added(path, _) => eprintln!("File: '{}' added", path),
+        BuildEvent::Skipped(issue) => eprintln!("Skipping input, {}", issue.description()),
+        BuildEvent::Duplicate(duplicate, policy) => {
+            let (path, what, original) = (&duplicate.path, duplicate.kind.description(), &duplicate.original_name);
+            match policy {
+                DuplicatePolicy::Skip => eprintln!("Skipping file: '{}' {} '{}'", path, what, original),
+                DuplicatePolicy::Warn => eprintln!("Warning: '{}' {} '{}', indexing it again", path, what, original),
+                DuplicatePolicy::Alias => eprintln!("Aliasing file: '{}' {} '{}'", path, what, original),
+            }
+        }
+        BuildEvent::Novelty(novelty) => match &novelty.nearest {
+            Some((_, name, similarity)) => eprintln!(
+                "Genome '{}' is {}, nearest '{}' at {:.4} ANI",
+                novelty.path,
+                if novelty.novel { "novel" } else { "known" },
+                name,
+                similarity.ani
+            ),
+            None => eprintln!("Genome '{}' is novel, no indexed genome shares positions with it", novelty.path),
+        },
+        BuildEvent::IgnoredTag(path, field) => eprintln!("Ignoring tag '{}' of '{}', expected key=value", field, path),
+    }
+}
+
+// Writer of the query and distance results, on --output or stdout
+fn open_writer(opts: &Options) -> Box<dyn ResultWriter> {
+    if opts.format.is_matrix() && opts.query.is_some() {
+        eprintln!("--format {} writes the --dist matrix, it does not apply to queries", opts.format);
+        exit(1);
+    }
+    if opts.format.is_matrix() && opts.sparse {
+        eprintln!("--sparse writes an edge list, use --format tsv, csv or jsonl");
+        exit(1);
+    }
+    if opts.format == Format::Phylip && !opts.symmetric && !opts.mash {
+        eprintln!("--format phylip holds symmetric distances and requires --symmetric or --mash");
+        exit(1);
+    }
+    let out: Box<dyn Write> = match &opts.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("Unable to create the file '{}': {}", path.display(), e);
+                exit(1);
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
+    output::new_writer(opts.format, out)
+}
+
+fn finish_writer(mut writer: Box<dyn ResultWriter>) {
+    if let Err(e) = writer.finish() {
+        eprintln!("Unable to write the results: {}", e);
+        exit(1);
+    }
+}
+
+// Newick tree of the genomes of the index, on --output or stdout
+fn write_tree<G: IndexInt, P: IndexInt>(index: &Index<G, P>, method: TreeMethod, bootstrap: usize, seed: u64, opts: &Options) {
+    let estimator = with_confidence(index.estimator(), opts);
+    let tree_of = |weights: Option<&[u32]>| {
+        let distances = index.distance_matrix(&estimator, weights).unwrap_or_else(|e| {
+            eprintln!("Unable to compare the genomes: {}", e);
+            exit(1);
+        });
+        Tree::build(method, &distances)
+    };
+    let mut tree = tree_of(None);
+    if bootstrap > 0 {
+        let nb_bins = index.params().sketch_size();
+        let replicates: Vec<Tree> = (0..bootstrap as u64)
+            .map(|replicate| {
+                let weights = resample_positions(index.get_position_range(), nb_bins, seed.wrapping_add(replicate));
+                tree_of(Some(&weights))
+            })
+            .collect();
+        tree.set_support(&replicates);
+    }
+    let names: Vec<&str> = index.get_all_metadata().iter().map(|metadata| metadata.name.as_str()).collect();
+    let newick = tree.to_newick(&names);
+    let written = match &opts.output {
+        Some(path) => std::fs::write(path, format!("{}\n", newick)),
+        None => writeln!(io::stdout(), "{}", newick),
+    };
+    if let Err(e) = written {
+        eprintln!("Unable to write the tree: {}", e);
+        exit(1);
+    }
+}
+
+// Cluster assignments on --output or stdout, representatives to their own file
+fn write_clusters<G: IndexInt, P: IndexInt>(
+    index: &Index<G, P>,
+    method: ClusterMethod,
+    min_ani: f64,
+    quality_tag: Option<&str>,
+    representatives: Option<&Path>,
+    opts: &Options,
+) {
+    let neighbors = index
+        .neighbors(&with_confidence(index.estimator(), opts), |similarity| similarity.ani >= min_ani)
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to compare the genomes: {}", e);
+            exit(1);
+        });
+    let neighbors: Vec<Vec<usize>> = neighbors.into_iter().map(|genome| genome.into_iter().map(|(gid, _)| gid).collect()).collect();
+
+    let scores = cluster::scores(index.get_all_metadata(), quality_tag);
+    let clustering = Clustering::build(method, &neighbors, &scores);
+
+    let genomes = index.get_all_metadata();
+    let mut assignments = String::from("genome\tcluster\trepresentative\n");
+    for (gid, &cluster) in clustering.cluster_of.iter().enumerate() {
+        let representative = &genomes[clustering.representatives[cluster]].name;
+        assignments.push_str(&format!("{}\t{}\t{}\n", genomes[gid].name, cluster, representative));
+    }
+    let written = match &opts.output {
+        Some(path) => std::fs::write(path, assignments),
+        None => io::stdout().write_all(assignments.as_bytes()),
+    };
+    if let Err(e) = written {
+        eprintln!("Unable to write the clusters: {}", e);
+        exit(1);
+    }
+
+    if let Some(path) = representatives {
+        let names: String = clustering.representatives.iter().map(|&gid| format!("{}\n", genomes[gid].name)).collect();
+        if let Err(e) = std::fs::write(path, names) {
+            eprintln!("Unable to write the representatives '{}': {}", path.display(), e);
+            exit(1);
+        }
+    }
+    eprintln!("{} genomes in {} clusters at {} ANI", index.get_nb_genomes(), clustering.representatives.len(), min_ani);
+}
+
+// k nearest neighbour graph on --output or stdout
+fn write_knn_graph<G: IndexInt, P: IndexInt>(index: &Index<G, P>, k: usize, format: GraphFormat, opts: &Options) {
+    let estimator = with_confidence(index.estimator(), opts);
+    let edges = index.nearest_neighbors(&estimator, k).unwrap_or_else(|e| {
+        eprintln!("Unable to compare the genomes: {}", e);
+        exit(1);
+    });
+    let names: Vec<&str> = index.get_all_metadata().iter().map(|metadata| metadata.name.as_str()).collect();
+    let mut out: Box<dyn Write> = match &opts.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(e) => {
+                eprintln!("Unable to create the file '{}': {}", path.display(), e);
+                exit(1);
+            }
+        },
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+    if let Err(e) = graph::write_graph(&mut out, format, &names, &edges).and_then(|_| out.flush()) {
+        eprintln!("Unable to write the graph: {}", e);
+        exit(1);
+    }
+}
+
+fn with_confidence(estimator: Estimator, opts: &Options) -> Estimator {
+    estimator.with_confidence(opts.confidence).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    })
+}
+
+fn print_info<G: IndexInt, P: IndexInt>(out: &mut dyn Write, monindex: &Index<G, P>, nb_genomes: usize) -> io::Result<()> {
+    writeln!(out, "+-------------------------------------------------------------------+")?;
+    writeln!(out, "|                            Informations                           |")?;
+    writeln!(out, "+-----------------------------------+-------------------------------+")?;
+    let params = monindex.params();
+    writeln!(out, "| k-mer size                        |{:>30} |", params.k)?;
+    writeln!(out, "| S                                 |{:>30} |", params.sketch_size())?;
+    writeln!(out, "| Number of fingerprints            |{:>30} |", params.fingerprint_range())?;
+    writeln!(out, "| W                                 |{:>30} |", params.w)?;
+    writeln!(out, "| E                                 |{:>30} |", params.e)?;
+    writeln!(out, "| Number of indexed genomes         |{:>30} |", nb_genomes)?;
+    writeln!(out, "+-------------------------------------------------------------------+")?;
+    writeln!(out, "|                                  Done                             |")?;
+    writeln!(out, "+-----------------------------------+-------------------------------+")
+}
+
+// Closing summary of a run, on stderr when the results went to stdout so
+// they can be piped without it
+fn print_run_info<G: IndexInt, P: IndexInt>(monindex: &Index<G, P>, nb_genomes: usize, opts: &Options) {
+    let results_on_stdout = (opts.query.is_some() || opts.dist) && opts.output.is_none();
+    let written = if results_on_stdout {
+        print_info(&mut io::stderr(), monindex, nb_genomes)
+    } else {
+        print_info(&mut io::stdout(), monindex, nb_genomes)
+    };
+    if let Err(e) = written {
+        eprintln!("Unable to write the summary: {}", e);
+        exit(1);
+    }
+}
+
+fn print_stats<G: IndexInt, P: IndexInt>(indexes: &[Index<G, P>], json: bool) {
+    for index in indexes {
+        let stats = IndexStats::new(index);
+        if json {
+            println!("{}", stats.to_json());
         } else {
+            print!("{}", stats);
+            if let Err(e) = print_info(&mut io::stdout(), index, index.get_nb_genomes()) {
+                eprintln!("Unable to write the summary: {}", e);
+                exit(1);
+            }
+        }
+    }
+}
+
+fn main() {
+    let opts: Options = Options::from_args();
+
+    // Index files are checked without being loaded, loading fails on corruption
+    if let Some(Command::Verify { files }) = &opts.command {
+        let mut nb_issues = 0;
+        for path in files {
+            let report = verify::verify_file(path);
+            for issue in &report.issues {
+                println!("{}\t{}", path.display(), issue);
+            }
+            if report.nb_issues > report.issues.len() {
+                println!("{}\t... {} more problems", path.display(), report.nb_issues - report.issues.len());
+            }
+            if report.nb_issues == 0 {
+                println!("{}\tOK", path.display());
+            }
+            nb_issues += report.nb_issues;
+        }
+        exit(if nb_issues == 0 { 0 } else { 1 });
+    }
+
+    // A loaded index keeps the widths it was built with
+    let widths = match opts.load.first() {
+        Some(path) => match rustic_onika::peek_header(path) {
+            Ok(header) => (header.id_bytes * 8, header.pos_bytes * 8),
+            Err(e) => {
+                eprintln!("Unable to load the index '{}': {}", path.display(), e);
+                exit(1);
+            }
+        },
+        None => (opts.id_width.unwrap_or(32), opts.pos_width.unwrap_or(16)),
+    };
+    if let Err(e) = width::with_widths(widths.0, widths.1, Run(opts)) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+// The command line, run with the widths of the index
+struct Run(Options);
+
+impl WidthVisitor for Run {
+    type Output = ();
+
+    fn visit<G: IndexInt, P: IndexInt>(self) {
+        run::<G, P>(self.0)
+    }
+}
+
+// Default --max-pvalue of --dist --sparse when no threshold is given
+const SPARSE_MAX_PVALUE: f64 = 1e-3;
+
+fn run<G: IndexInt, P: IndexInt>(opts: Options) {
+
+    if opts.load.len() > 1 {
+        if opts.index.is_some() || opts.dist || matches!(opts.command, Some(Command::Tree { .. } | Command::Cluster { .. } | Command::Knn { .. })) {
+            eprintln!("-I, --dist, tree, cluster and knn need a single index, not a set of shards");
+            exit(1);
+        }
+        let shards = ShardSet::<G, P>::load(&opts.load).unwrap_or_else(|e| {
+            eprintln!("Unable to load the shards: {}", e);
+            exit(1);
+        });
+        if let Some(query_file) = &opts.query {
+            let estimator = with_confidence(shards.estimator(), &opts);
+            let mut writer = open_writer(&opts);
+            query_files(query_file, shards.get_index(), &estimator, &opts, writer.as_mut(), |sketch| {
+                shards.query_sketch(sketch)
+            });
+            finish_writer(writer);
+        }
+        if let Some(Command::Info { json }) = opts.command {
+            print_stats(shards.get_shards(), json);
+            return;
+        }
+        print_run_info(shards.get_index(), shards.get_nb_genomes(), &opts);
+        return;
+    }
+
+    let mut monindex = match opts.load.first() {
+        Some(path) => Index::<G, P>::load(path).unwrap_or_else(|e| {
+            eprintln!("Unable to load the index '{}': {}", path.display(), e);
+            exit(1);
+        }),
+        None => {
+            let defaults = Params::default();
+            Index::new(Params {
+                k: opts.kmer.map_or(defaults.k, |k| k as u32),
+                lf: opts.sketch.map_or(defaults.lf, |lf| lf as u32),
+                w: opts.word.map_or(defaults.w, |w| w as u32),
+                e: opts.egs.map_or(defaults.e, |e| e as u32),
+            })
+        }
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to create the index: {}", e);
+            exit(1);
+        }),
+    };
+    let mut nb_genomes = monindex.get_nb_genomes();
+
+    // A single shard only holds some of the sketch positions of each genome
+    let (shard, nb_shards) = match (opts.load.is_empty(), opts.shard, opts.shards) {
+        (true, Some(shard), Some(nb_shards)) => (shard, nb_shards),
+        _ => monindex.get_shard(),
+    };
+    if nb_shards > 1 {
+        if opts.dist || matches!(opts.command, Some(Command::Tree { .. } | Command::Cluster { .. } | Command::Knn { .. })) {
+            eprintln!(
+                "--dist, tree, cluster and knn compare genomes on every sketch position, not on shard {} of {} alone",
+                shard, nb_shards
+            );
+            exit(1);
+        }
+        if opts.query.is_some() {
+            eprintln!(
+                "Warning: only shard {} of {} is queried, give every shard file to -L to query the whole index",
+                shard, nb_shards
+            );
+        }
+    }
+
+    if let Some(list_file) = &opts.index {
+        if File::open(list_file).is_err() {
             eprintln!("Unable to open the file '{}'", list_file.display());
             exit(1);
         }
-         monindex.get_filename(list_file.to_str().unwrap());
-     }
+        match (opts.shard, opts.shards) {
+            (Some(_), Some(_)) if !opts.load.is_empty() => {
+                eprintln!("--shard builds a new shard and cannot be used with -L, a loaded index keeps its shard");
+                exit(1);
+            }
+            (Some(shard), Some(nb_shards)) if shard < nb_shards => {
+                if let Err(e) = monindex.set_shard(shard, nb_shards) {
+                    eprintln!("Invalid shard: {}", e);
+                    exit(1);
+                }
+            }
+            (None, None) => {}
+            _ => {
+                eprintln!("--shard j and --shards n go together, with j < n");
+                exit(1);
+            }
+        }
+        if let Some(min_score) = opts.min_score {
+            monindex.set_min_score(min_score);
+        }
+        // Duplicate sketches are compared on all their positions, so that
+        // every shard of a build skips the same genomes
+        let mut duplicates = monindex.duplicate_finder(opts.duplicates);
+        let mut inputs = InputReport::new(opts.strict);
+        let mut novelty = (opts.novelty || opts.novelty_report.is_some()).then(|| NoveltyReport::new(opts.novelty_ani));
+        if let Some(max_memory) = opts.max_memory {
+            if novelty.is_some() {
+                eprintln!("--novelty queries the index while it is built and does not work with --max-memory");
+                exit(1);
+            }
+            let output = opts.output_index.as_ref().unwrap_or_else(|| {
+                eprintln!("--max-memory writes the index straight to disk and requires -O");
+                exit(1);
+            });
+            if !opts.load.is_empty() {
+                eprintln!("--max-memory builds a new index and cannot add genomes to one loaded with -L");
+                exit(1);
+            }
+            let tmp_dir = opts.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+            let mut builder = ExternalBuilder::new(&monindex, max_memory << 20, &tmp_dir);
+            let built = builder
+                .get_filename(list_file.to_str().unwrap(), &mut duplicates, &mut inputs, &mut print_progress)
+                .and_then(|_| {
+                    nb_genomes = builder.get_nb_genomes();
+                    builder.finish(output)
+                });
+            if let Err(e) = built {
+                eprintln!("Out of core build failed: {}", e);
+                exit(1);
+            }
+            // The queries and the commands read the index just written
+            if opts.dist || opts.query.is_some() || opts.command.is_some() {
+                monindex = Index::load(output).unwrap_or_else(|e| {
+                    eprintln!("Unable to load the index '{}': {}", output.display(), e);
+                    exit(1);
+                });
+            }
+        } else {
+            if let Err(e) = monindex.get_filename(
+                list_file.to_str().unwrap(),
+                &mut duplicates,
+                &mut inputs,
+                novelty.as_mut(),
+                &mut print_progress,
+            ) {
+                eprintln!("Build failed: {}", e);
+                exit(1);
+            }
+            nb_genomes = monindex.get_nb_genomes();
+            if let Some(output) = &opts.output_index {
+                if let Err(e) = monindex.dump(output) {
+                    eprintln!("Unable to write the index '{}': {}", output.display(), e);
+                    exit(1);
+                }
+            }
+        }
+        if !duplicates.found().is_empty() {
+            eprintln!("{}", duplicates);
+        }
+        if !inputs.issues().is_empty() {
+            eprintln!("{}", inputs);
+        }
+        if let Some(report) = &opts.skip_errors {
+            if let Err(e) = inputs.write_tsv(report) {
+                eprintln!("Unable to write the input report '{}': {}", report.display(), e);
+                exit(1);
+            }
+        }
+        if let Some(novelty) = &novelty {
+            eprintln!("{}", novelty);
+            if let Some(report) = &opts.novelty_report {
+                if let Err(e) = novelty.write_tsv(report) {
+                    eprintln!("Unable to write the novelty report '{}': {}", report.display(), e);
+                    exit(1);
+                }
+            }
+        }
+    }
 
-    if opts.dist {
-        monindex.print_matrix();
+    if opts.query.is_some() || opts.dist {
+        let estimator = with_confidence(monindex.estimator(), &opts);
+        let mut writer = open_writer(&opts);
+        if let Some(query_file) = &opts.query {
+            query_files(query_file, &monindex, &estimator, &opts, writer.as_mut(), |sketch| {
+                monindex.query_sketch(sketch)
+            });
+        }
+        if opts.dist {
+            let written = if opts.sparse {
+                let min_score = opts.min_score.unwrap_or_else(|| monindex.get_min_score()).max(1);
+                let min_ani = opts.min_ani.unwrap_or(0.0);
+                // Without any threshold every pair sharing a position would be written
+                let max_pvalue = match (opts.min_ani, opts.max_pvalue) {
+                    (_, Some(max_pvalue)) => max_pvalue,
+                    (Some(_), None) => 1.0,
+                    (None, None) => SPARSE_MAX_PVALUE,
+                };
+                monindex.write_sparse_distances(
+                    &estimator,
+                    |similarity| similarity.shared >= min_score && similarity.ani >= min_ani && similarity.pvalue <= max_pvalue,
+                    writer.as_mut(),
+                )
+            } else {
+                let values = if opts.mash {
+                    DistanceMatrix::Mash
+                } else if opts.symmetric {
+                    DistanceMatrix::Jaccard
+                } else {
+                    DistanceMatrix::Containment
+                };
+                monindex.write_distances(&estimator, values, writer.as_mut())
+            };
+            if let Err(e) = written {
+                eprintln!("Unable to write the results: {}", e);
+                exit(1);
+            }
+        }
+        finish_writer(writer);
+    }
+
+    if let Some(Command::Tree { method, bootstrap, seed }) = opts.command {
+        write_tree(&monindex, method, bootstrap, seed, &opts);
+        return;
+    }
+
+    if let Some(Command::Cluster { method, min_ani, quality_tag, representatives }) = &opts.command {
+        write_clusters(&monindex, *method, *min_ani, quality_tag.as_deref(), representatives.as_deref(), &opts);
+        return;
+    }
+
+    if let Some(Command::Knn { k, format }) = opts.command {
+        write_knn_graph(&monindex, k, format, &opts);
+        return;
+    }
+
+    if let Some(Command::Info { json }) = opts.command {
+        print_stats(std::slice::from_ref(&monindex), json);
+        return;
     }
   
     
@@ -98,17 +877,5 @@ fn main() {
         exit(0);
     }
 
-    println!("+-------------------------------------------------------------------+");
-    println!("|                            Informations                           |");
-    println!("+-----------------------------------+-------------------------------+");
-println!("| k-mer size                        |{:>30} |", monindex.get_k());
-println!("| S                                 |{:>30} |", monindex.get_f());
-println!("| Number of fingerprints            |{:>30} |", monindex.get_fingerprint_range());
-println!("| W                                 |{:>30} |", monindex.get_w());
-println!("| E                                 |{:>30} |", monindex.get_e());
-println!("| Number of indexed genomes         |{:>30} |", monindex.get_nb_genomes());
-    println!("+-------------------------------------------------------------------+");
-    println!("|                                  Done                             |");
-    println!("+-----------------------------------+-------------------------------+");
+    print_run_info(&monindex, nb_genomes, &opts);
 }
-
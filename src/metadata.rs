@@ -0,0 +1,155 @@
+use std::io::{self, Read, Write};
+
+use crate::storage::{self, invalid_data, Remaining};
+
+/// What is known about an indexed genome, stored by Gid next to its sketch
+#[derive(Clone, Default)]
+pub struct GenomeMetadata {
+    pub name: String,
+    pub paths: Vec<String>,
+    pub nb_sequences: u64,
+    pub total_bases: u64,
+    pub cardinality: u64,       // estimated number of distinct k-mers
+    pub gc_content: f64,
+    pub tags: Vec<(String, String)>,
+}
+
+impl GenomeMetadata {
+    pub fn new(path: &str, sequences: &[String], cardinality: u64) -> GenomeMetadata {
+        let mut gc = 0u64;
+        let mut acgt = 0u64;
+        for sequence in sequences {
+            for &nuc in sequence.as_bytes() {
+                match nuc {
+                    b'C' | b'c' | b'G' | b'g' => {
+                        gc += 1;
+                        acgt += 1;
+                    }
+                    b'A' | b'a' | b'T' | b't' => acgt += 1,
+                    _ => {}
+                }
+            }
+        }
+        GenomeMetadata {
+            name: path.to_string(),
+            paths: vec![path.to_string()],
+            nb_sequences: sequences.len() as u64,
+            total_bases: sequences.iter().map(|sequence| sequence.len() as u64).sum(),
+            cardinality,
+            gc_content: if acgt == 0 { 0.0 } else { gc as f64 / acgt as f64 },
+            tags: Vec::new(),
+        }
+    }
+
+    /// Fields of the genome shown next to query and distance results
+    pub const COLUMNS: [&'static str; 5] = ["sequences", "bases", "kmers", "gc", "tags"];
+
+    /// Fields in the order of COLUMNS, tags as key=value separated by ';'
+    pub fn values(&self) -> [String; 5] {
+        [
+            self.nb_sequences.to_string(),
+            self.total_bases.to_string(),
+            self.cardinality.to_string(),
+            format!("{:.4}", self.gc_content),
+            self.tags_string(),
+        ]
+    }
+
+    fn tags_string(&self) -> String {
+        let tags: Vec<String> = self.tags.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+        tags.join(";")
+    }
+
+    /// Tab separated: name, paths, sequences, bases, k-mers, GC content, tags
+    pub fn to_tsv(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{:.4}\t{}",
+            self.name,
+            self.paths.join(","),
+            self.nb_sequences,
+            self.total_bases,
+            self.cardinality,
+            self.gc_content,
+            self.tags_string()
+        )
+    }
+
+    /// A "name" tag replaces the default name, the path of the genome
+    pub fn add_tags(&mut self, tags: Vec<(String, String)>) {
+        for (key, value) in tags {
+            if key == "name" {
+                self.name = value.clone();
+            }
+            self.tags.push((key, value));
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write_string(writer, &self.name)?;
+        storage::write_u32(writer, self.paths.len() as u32)?;
+        for path in &self.paths {
+            write_string(writer, path)?;
+        }
+        storage::write_u64(writer, self.nb_sequences)?;
+        storage::write_u64(writer, self.total_bases)?;
+        storage::write_u64(writer, self.cardinality)?;
+        storage::write_u64(writer, self.gc_content.to_bits())?;
+        storage::write_u32(writer, self.tags.len() as u32)?;
+        for (key, value) in &self.tags {
+            write_string(writer, key)?;
+            write_string(writer, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read + Remaining>(reader: &mut R) -> io::Result<GenomeMetadata> {
+        let name = read_string(reader)?;
+        let nb_paths = storage::read_u32(reader)?;
+        let nb_paths = storage::check_count(reader, nb_paths as u64, 4, "paths")?;
+        let paths = storage::read_items(reader, nb_paths, |reader| read_string(reader))?;
+        let nb_sequences = storage::read_u64(reader)?;
+        let total_bases = storage::read_u64(reader)?;
+        let cardinality = storage::read_u64(reader)?;
+        let gc_content = f64::from_bits(storage::read_u64(reader)?);
+        let nb_tags = storage::read_u32(reader)?;
+        let nb_tags = storage::check_count(reader, nb_tags as u64, 8, "tags")?;
+        let tags = storage::read_items(reader, nb_tags, |reader| Ok((read_string(reader)?, read_string(reader)?)))?;
+        Ok(GenomeMetadata {
+            name,
+            paths,
+            nb_sequences,
+            total_bases,
+            cardinality,
+            gc_content,
+            tags,
+        })
+    }
+}
+
+/// A line of a file of files: the genome path, then optional tab separated
+/// key=value tags. Fields without '=' come back apart, they are ignored.
+pub fn parse_fof_line(line: &str) -> (String, Vec<(String, String)>, Vec<String>) {
+    let mut fields = line.split('\t');
+    let path = fields.next().unwrap_or("").trim().to_string();
+    let mut tags = Vec::new();
+    let mut ignored = Vec::new();
+    for field in fields {
+        match field.split_once('=') {
+            Some((key, value)) => tags.push((key.trim().to_string(), value.trim().to_string())),
+            None => ignored.push(field.to_string()),
+        }
+    }
+    (path, tags, ignored)
+}
+
+fn write_string<W: Write>(writer: &mut W, string: &str) -> io::Result<()> {
+    storage::write_u32(writer, string.len() as u32)?;
+    writer.write_all(string.as_bytes())
+}
+
+fn read_string<R: Read + Remaining>(reader: &mut R) -> io::Result<String> {
+    let len = storage::read_u32(reader)?;
+    let len = storage::check_count(reader, len as u64, 1, "string bytes")?;
+    let bytes = storage::read_bytes(reader, len)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data(String::from("metadata string is not UTF-8")))
+}
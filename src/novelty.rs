@@ -0,0 +1,167 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::error::Result;
+use crate::index::{Gid, Index};
+use crate::similarity::Similarity;
+use crate::sketcher::Sketch;
+use crate::width::IndexInt;
+
+/// How an incoming genome compares to the genomes indexed before it
+pub struct Novelty {
+    pub path: String,
+    pub nearest: Option<(Gid, String, Similarity)>, // closest genome by ANI, if any shares positions
+    pub novel: bool,
+}
+
+/// Novelty of the genomes of a build, each one queried against the index
+/// right before its insertion. Genomes below `min_ani` of every indexed
+/// genome are novel.
+pub struct NoveltyReport {
+    min_ani: f64,
+    counts: Vec<u32>, // query scratch space, see Index::query_sketch_sparse
+    genomes: Vec<Novelty>,
+}
+
+impl NoveltyReport {
+    pub fn new(min_ani: f64) -> NoveltyReport {
+        NoveltyReport {
+            min_ani,
+            counts: Vec::new(),
+            genomes: Vec::new(),
+        }
+    }
+
+    /// Query `sketch` against `index` before it is inserted
+    pub fn add<G: IndexInt, P: IndexInt>(&mut self, index: &Index<G, P>, path: &str, sketch: &Sketch, cardinality: u64) -> Result<&Novelty> {
+        let estimator = index.estimator();
+        let min_score = index.get_min_score().max(1);
+        let genomes = index.get_all_metadata();
+        let nearest = index
+            .query_sketch_sparse(sketch, &mut self.counts)?
+            .into_iter()
+            .filter(|hit| hit.count >= min_score)
+            .map(|hit| (hit.gid, estimator.estimate(hit.count, cardinality, genomes[hit.gid].cardinality)))
+            .fold(None, |best: Option<(Gid, Similarity)>, (gid, similarity)| match best {
+                Some((_, ref best_similarity)) if best_similarity.ani >= similarity.ani => best,
+                _ => Some((gid, similarity)),
+            })
+            .map(|(gid, similarity)| (gid, genomes[gid].name.clone(), similarity));
+        let novel = nearest.as_ref().is_none_or(|(_, _, similarity)| similarity.ani < self.min_ani);
+
+        self.genomes.push(Novelty {
+            path: path.to_string(),
+            nearest,
+            novel,
+        });
+        Ok(&self.genomes[self.genomes.len() - 1])
+    }
+
+    /// Tab separated: path, nearest genome, its ANI and shared positions, status
+    pub fn write_tsv(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "genome\tnearest\tani\tshared\tstatus")?;
+        for genome in &self.genomes {
+            let status = if genome.novel { "novel" } else { "known" };
+            match &genome.nearest {
+                Some((_, name, similarity)) => writeln!(
+                    writer,
+                    "{}\t{}\t{:.4}\t{}\t{}",
+                    genome.path, name, similarity.ani, similarity.shared, status
+                )?,
+                None => writeln!(writer, "{}\t-\t-\t0\t{}", genome.path, status)?,
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Number of genomes added, novel and known
+impl fmt::Display for NoveltyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let novel = self.genomes.iter().filter(|genome| genome.novel).count();
+        write!(
+            f,
+            "{} genomes added: {} novel, {} known at {} ANI",
+            self.genomes.len(),
+            novel,
+            self.genomes.len() - novel,
+            self.min_ani
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::GenomeMetadata;
+    use crate::sketcher::Params;
+
+    const PARAMS: Params = Params { k: 15, lf: 8, w: 8, e: 1000 };
+
+    // Genome g shares the bins of genome 0 on a fraction of the positions
+    // decreasing with g
+    fn sketch(g: u64) -> Sketch {
+        Sketch::from_bins(
+            (0..256u64)
+                .map(|i| if (i * 37 + g * 11) % 9 >= g { (i * 7) % 256 } else { (i * 7 + g * 53) % 256 })
+                .collect(),
+        )
+    }
+
+    fn index_of_first_genome() -> Index {
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        index.insert_genome(&sketch(0), GenomeMetadata::new("g0", &[], 1000)).unwrap();
+        index
+    }
+
+    #[test]
+    fn first_genome_has_no_nearest_genome() {
+        let index: Index = Index::new(PARAMS).unwrap();
+        let mut report = NoveltyReport::new(0.0);
+        let novelty = report.add(&index, "g0", &sketch(0), 1000).unwrap();
+        assert!(novelty.nearest.is_none());
+        assert!(novelty.novel);
+    }
+
+    #[test]
+    fn genomes_at_the_threshold_are_known() {
+        let index = index_of_first_genome();
+        let ani = NoveltyReport::new(0.0).add(&index, "g3", &sketch(3), 1000).unwrap().nearest.as_ref().unwrap().2.ani;
+        assert!(ani > 0.5 && ani < 1.0, "{}", ani);
+
+        let mut at = NoveltyReport::new(ani);
+        let novelty = at.add(&index, "g3", &sketch(3), 1000).unwrap();
+        assert!(!novelty.novel);
+        assert_eq!(novelty.nearest.as_ref().map(|(gid, name, _)| (*gid, name.as_str())), Some((0, "g0")));
+        let mut above = NoveltyReport::new(ani + 1e-9);
+        assert!(above.add(&index, "g3", &sketch(3), 1000).unwrap().novel);
+        let mut same = NoveltyReport::new(1.0);
+        assert!(!same.add(&index, "copy", &sketch(0), 1000).unwrap().novel);
+    }
+
+    #[test]
+    fn report_lists_every_genome() {
+        let path = std::env::temp_dir().join(format!("onika-novelty-{}.tsv", std::process::id()));
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        let mut report = NoveltyReport::new(0.95);
+        for (g, name) in [(0, "g0"), (1, "g1"), (8, "g8")] {
+            report.add(&index, name, &sketch(g), 1000).unwrap();
+            index.insert_genome(&sketch(g), GenomeMetadata::new(name, &[], 1000)).unwrap();
+        }
+        report.write_tsv(&path).unwrap();
+        let tsv = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Vec<&str>> = tsv.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(lines[0], ["genome", "nearest", "ani", "shared", "status"]);
+        assert_eq!(lines[1], ["g0", "-", "-", "0", "novel"]);
+        assert_eq!(lines[2][..2], ["g1", "g0"]);
+        assert_eq!(lines[2][4], "known");
+        assert_eq!(lines[3][4], "novel");
+        assert!(lines[2..].iter().all(|line| line[2].parse::<f64>().is_ok() && line[3].parse::<u32>().unwrap() > 0));
+        assert_eq!(lines.len(), 4);
+        assert_eq!(report.to_string(), "3 genomes added: 2 novel, 1 known at 0.95 ANI");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::metadata::GenomeMetadata;
+use crate::similarity::Similarity;
+
+/// Layout of query and distance results
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tsv,    // one query/reference pair per line, with a header line
+    Csv,    // same as Tsv, comma separated
+    Jsonl,  // one JSON object per pair
+    Phylip, // symmetric distance matrix, relaxed PHYLIP
+    Matrix, // tab separated matrix after ##Genome and ##Names lines
+}
+
+impl Format {
+    /// Matrix formats only write the --dist matrix, not pairs
+    pub fn is_matrix(&self) -> bool {
+        matches!(self, Format::Phylip | Format::Matrix)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "tsv" => Ok(Format::Tsv),
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            "phylip" => Ok(Format::Phylip),
+            "matrix" => Ok(Format::Matrix),
+            _ => Err(format!("unknown format '{}', expected tsv, csv, jsonl, phylip or matrix", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Format::Tsv => "tsv",
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            Format::Phylip => "phylip",
+            Format::Matrix => "matrix",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Receives results as query/reference pairs and, for --dist, as a
+/// matrix. Pair formats ignore the matrix and matrix formats the pairs.
+pub trait ResultWriter {
+    /// Pair formats write the name and the GenomeMetadata::COLUMNS of both genomes
+    fn write_pair(&mut self, _query: &GenomeMetadata, _reference: &GenomeMetadata, _similarity: &Similarity) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// `values[i][j]` compares genome i to genome j: a distance, or a
+    /// containment for asymmetric matrices
+    fn write_matrix(&mut self, _genomes: &[&GenomeMetadata], _values: &[Vec<f64>]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Must be called once everything is written
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Writer of `format` on `out`
+pub fn new_writer(format: Format, out: Box<dyn Write>) -> Box<dyn ResultWriter> {
+    match format {
+        Format::Tsv => Box::new(DelimitedWriter::new(out, '\t')),
+        Format::Csv => Box::new(DelimitedWriter::new(out, ',')),
+        Format::Jsonl => Box::new(JsonLinesWriter { out }),
+        Format::Phylip => Box::new(PhylipWriter { out }),
+        Format::Matrix => Box::new(MatrixWriter { out }),
+    }
+}
+
+struct DelimitedWriter {
+    out: Box<dyn Write>,
+    separator: char,
+    header_written: bool,
+}
+
+impl DelimitedWriter {
+    fn new(out: Box<dyn Write>, separator: char) -> DelimitedWriter {
+        DelimitedWriter {
+            out,
+            separator,
+            header_written: false,
+        }
+    }
+
+    // CSV fields holding a separator, a quote or a line break are quoted
+    fn field<'a>(&self, value: &'a str) -> std::borrow::Cow<'a, str> {
+        if self.separator == ',' && value.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", value.replace('"', "\"\"")).into()
+        } else {
+            value.into()
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.header_written = true;
+            let mut columns: Vec<String> = vec![String::from("query"), String::from("reference")];
+            columns.extend(Similarity::COLUMNS.iter().map(|column| column.to_string()));
+            for genome in ["query", "reference"] {
+                columns.extend(GenomeMetadata::COLUMNS.iter().map(|column| format!("{}_{}", genome, column)));
+            }
+            writeln!(self.out, "{}", columns.join(&self.separator.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl ResultWriter for DelimitedWriter {
+    fn write_pair(&mut self, query: &GenomeMetadata, reference: &GenomeMetadata, similarity: &Similarity) -> io::Result<()> {
+        self.write_header()?;
+        let mut fields = vec![self.field(&query.name).into_owned(), self.field(&reference.name).into_owned()];
+        fields.extend(similarity.values());
+        for genome in [query, reference] {
+            fields.extend(genome.values().iter().map(|value| self.field(value).into_owned()));
+        }
+        writeln!(self.out, "{}", fields.join(&self.separator.to_string()))
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.out.flush()
+    }
+}
+
+struct JsonLinesWriter {
+    out: Box<dyn Write>,
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Metadata fields of a genome, keys starting with `prefix` and tags as an object
+fn json_metadata(prefix: &str, genome: &GenomeMetadata) -> String {
+    let tags: Vec<String> = genome
+        .tags
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect();
+    format!(
+        "\"{0}_sequences\":{1},\"{0}_bases\":{2},\"{0}_kmers\":{3},\"{0}_gc\":{4:.4},\"{0}_tags\":{{{5}}}",
+        prefix,
+        genome.nb_sequences,
+        genome.total_bases,
+        genome.cardinality,
+        genome.gc_content,
+        tags.join(",")
+    )
+}
+
+impl ResultWriter for JsonLinesWriter {
+    fn write_pair(&mut self, query: &GenomeMetadata, reference: &GenomeMetadata, similarity: &Similarity) -> io::Result<()> {
+        let fields: Vec<String> = Similarity::COLUMNS
+            .iter()
+            .zip(similarity.values())
+            .map(|(column, value)| format!("\"{}\":{}", column, value))
+            .collect();
+        writeln!(
+            self.out,
+            "{{\"query\":{},\"reference\":{},{},{},{}}}",
+            json_string(&query.name),
+            json_string(&reference.name),
+            fields.join(","),
+            json_metadata("query", query),
+            json_metadata("reference", reference)
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Relaxed PHYLIP: the number of genomes, then one row per genome starting
+/// with its name, whitespace replaced by '_'
+struct PhylipWriter {
+    out: Box<dyn Write>,
+}
+
+impl ResultWriter for PhylipWriter {
+    fn write_matrix(&mut self, genomes: &[&GenomeMetadata], distances: &[Vec<f64>]) -> io::Result<()> {
+        writeln!(self.out, "{}", genomes.len())?;
+        for (genome, row) in genomes.iter().zip(distances) {
+            let name: String = genome.name.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect();
+            write!(self.out, "{}", name)?;
+            for distance in row {
+                write!(self.out, " {:.6}", distance)?;
+            }
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+struct MatrixWriter {
+    out: Box<dyn Write>,
+}
+
+impl ResultWriter for MatrixWriter {
+    fn write_matrix(&mut self, genomes: &[&GenomeMetadata], values: &[Vec<f64>]) -> io::Result<()> {
+        writeln!(self.out, "##Genome\tname\tpaths\tsequences\tbases\tkmers\tgc\ttags")?;
+        for genome in genomes {
+            writeln!(self.out, "##Genome\t{}", genome.to_tsv())?;
+        }
+        write!(self.out, "##Names ")?;
+        for genome in genomes {
+            write!(self.out, "{}\t", genome.name)?;
+        }
+        writeln!(self.out)?;
+
+        for (i, row) in values.iter().enumerate() {
+            write!(self.out, "{}\t", genomes[i].name)?;
+            for (j, value) in row.iter().enumerate() {
+                if i == j {
+                    write!(self.out, "-\t")?;
+                } else {
+                    write!(self.out, "{:.4}\t", value)?;
+                }
+            }
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::Estimator;
+    use crate::sketcher::Params;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Output shared with the test once the writer owns its Box
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn pair_output(format: Format, query: &GenomeMetadata, reference: &GenomeMetadata) -> String {
+        let out = Shared::default();
+        let mut writer = new_writer(format, Box::new(out.clone()));
+        let estimator = Estimator::new(Params::default(), Params::default().sketch_size());
+        writer.write_pair(query, reference, &estimator.estimate(100, 5000, 6000)).unwrap();
+        writer.finish().unwrap();
+        let bytes = out.0.borrow().clone();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    fn genomes() -> (GenomeMetadata, GenomeMetadata) {
+        let query = GenomeMetadata::new("q.fasta", &["ACGT".to_string()], 5000);
+        let mut reference = GenomeMetadata::new("r.fasta", &["GGCC".to_string(), "AT".to_string()], 6000);
+        reference.add_tags(vec![(String::from("species"), String::from("E. coli")), (String::from("quality"), String::from("0.9"))]);
+        (query, reference)
+    }
+
+    #[test]
+    fn delimited_pairs_carry_the_metadata_of_both_genomes() {
+        let (query, reference) = genomes();
+        let output = pair_output(Format::Tsv, &query, &reference);
+        let lines: Vec<Vec<&str>> = output.lines().map(|line| line.split('\t').collect()).collect();
+        assert_eq!(lines.len(), 2);
+        let columns = 2 + Similarity::COLUMNS.len() + 2 * GenomeMetadata::COLUMNS.len();
+        assert_eq!(lines[0].len(), columns);
+        assert_eq!(lines[1].len(), columns);
+        assert_eq!(&lines[0][columns - 10..columns - 5], ["query_sequences", "query_bases", "query_kmers", "query_gc", "query_tags"]);
+        assert_eq!(&lines[1][..2], ["q.fasta", "r.fasta"]);
+        assert_eq!(&lines[1][columns - 10..columns - 5], ["1", "4", "5000", "0.5000", ""]);
+        assert_eq!(&lines[1][columns - 5..], ["2", "6", "6000", "0.6667", "species=E. coli;quality=0.9"]);
+
+        let csv = pair_output(Format::Csv, &query, &reference);
+        assert_eq!(csv.lines().nth(1).unwrap().split(',').count(), columns);
+    }
+
+    #[test]
+    fn json_pairs_carry_the_metadata_of_both_genomes() {
+        let (query, reference) = genomes();
+        let output = pair_output(Format::Jsonl, &query, &reference);
+        assert_eq!(output.lines().count(), 1);
+        assert!(output.starts_with("{\"query\":\"q.fasta\",\"reference\":\"r.fasta\",\"shared\":100,"));
+        assert!(output.contains("\"query_sequences\":1,\"query_bases\":4,\"query_kmers\":5000,\"query_gc\":0.5000,\"query_tags\":{}"));
+        assert!(output.contains(
+            "\"reference_sequences\":2,\"reference_bases\":6,\"reference_kmers\":6000,\"reference_gc\":0.6667,\
+             \"reference_tags\":{\"species\":\"E. coli\",\"quality\":\"0.9\"}}\n"
+        ));
+    }
+
+    #[test]
+    fn csv_fields_are_quoted_when_needed() {
+        let writer = DelimitedWriter::new(Box::new(io::sink()), ',');
+        assert_eq!(writer.field("plain name"), "plain name");
+        assert_eq!(writer.field("a,b"), "\"a,b\"");
+        assert_eq!(writer.field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(writer.field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(writer.field("cr\r"), "\"cr\r\"");
+        let tsv = DelimitedWriter::new(Box::new(io::sink()), '\t');
+        assert_eq!(tsv.field("a,\"b\""), "a,\"b\"");
+
+        let (mut query, reference) = genomes();
+        query.name = String::from("q,1 \"x\"");
+        let csv = pair_output(Format::Csv, &query, &reference);
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.starts_with("\"q,1 \"\"x\"\"\",r.fasta,100,"), "{}", row);
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\"b\\c"), "\"a\\\"b\\\\c\"");
+        assert_eq!(json_string("l1\nl2\r\t"), "\"l1\\nl2\\r\\t\"");
+        assert_eq!(json_string("\u{1}\u{1f}"), "\"\\u0001\\u001f\"");
+        assert_eq!(json_string("é ∞"), "\"é ∞\"");
+
+        let (mut query, mut reference) = genomes();
+        query.name = String::from("q\"\n");
+        reference.tags.push((String::from("k\\"), String::from("v\t")));
+        let json = pair_output(Format::Jsonl, &query, &reference);
+        assert_eq!(json.lines().count(), 1);
+        assert!(json.starts_with("{\"query\":\"q\\\"\\n\",\"reference\":\"r.fasta\","), "{}", json);
+        assert!(json.contains("\"k\\\\\":\"v\\t\"}"), "{}", json);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::index::Gid;
+
+/// A genome sharing sketch positions with a query, laid out as onika_hit for
+/// the C interface
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hit {
+    pub gid: Gid,
+    /// Number of sketch positions with the same fingerprint in both sketches
+    pub count: u32,
+}
+
+/// Result of a query: the number of sketch positions it shares with every
+/// genome of the index, indexed by Gid
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryResult {
+    counts: Vec<u32>,
+}
+
+impl QueryResult {
+    pub fn new(counts: Vec<u32>) -> QueryResult {
+        QueryResult { counts }
+    }
+
+    pub fn counts(&self) -> &[u32] {
+        &self.counts
+    }
+
+    pub fn get_count(&self, gid: Gid) -> u32 {
+        self.counts[gid]
+    }
+
+    /// Genomes sharing at least one position with the query, by Gid
+    pub fn hits(&self) -> impl Iterator<Item = Hit> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(gid, &count)| Hit { gid, count })
+    }
+
+    /// Genomes sharing at least `min_score` positions with the query, and at
+    /// least one, by decreasing count then Gid. With `top`, only the `top`
+    /// best are kept, see rank.
+    pub fn ranked(&self, min_score: u32, top: Option<usize>) -> Vec<Hit> {
+        let min_score = min_score.max(1);
+        rank(self.hits().filter(|hit| hit.count >= min_score).collect(), top)
+    }
+
+    /// Add the counts of a query on another shard of the same index
+    pub fn merge(&mut self, other: &QueryResult) {
+        for (total, &count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *total += count;
+        }
+    }
+}
+
+/// Sort `hits` by decreasing count then Gid. With `top`, only the `top` best
+/// are kept: they are picked by partial selection and only them are sorted.
+pub fn rank(mut hits: Vec<Hit>, top: Option<usize>) -> Vec<Hit> {
+    let order = |a: &Hit, b: &Hit| b.count.cmp(&a.count).then(a.gid.cmp(&b.gid));
+    if let Some(top) = top {
+        if top < hits.len() {
+            if top > 0 {
+                hits.select_nth_unstable_by(top - 1, order);
+            }
+            hits.truncate(top);
+        }
+    }
+    hits.sort_unstable_by(order);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Many ties, some genomes without any shared position
+    fn result() -> QueryResult {
+        QueryResult::new((0..200u32).map(|gid| (gid * 37 + 11) % 13).collect())
+    }
+
+    // Every hit passing `min_score`, fully sorted
+    fn reference(result: &QueryResult, min_score: u32) -> Vec<Hit> {
+        let mut hits: Vec<Hit> = result.hits().filter(|hit| hit.count >= min_score).collect();
+        hits.sort_by(|a, b| b.count.cmp(&a.count).then(a.gid.cmp(&b.gid)));
+        hits
+    }
+
+    #[test]
+    fn ranked_keeps_the_best_hits_in_order() {
+        let result = result();
+        let all = reference(&result, 1);
+        assert!(all.len() > 100 && all.len() < 200);
+        assert_eq!(result.ranked(0, None), all);
+        for top in [1, 5, 17, 100, all.len() - 1] {
+            assert_eq!(result.ranked(0, Some(top)), all[..top], "top {}", top);
+        }
+        assert_eq!(result.ranked(0, Some(all.len())), all);
+        assert_eq!(result.ranked(0, Some(1000)), all);
+        assert!(result.ranked(0, Some(0)).is_empty());
+    }
+
+    #[test]
+    fn ties_come_by_increasing_gid() {
+        let ranked = QueryResult::new(vec![3, 5, 0, 5, 3, 5]).ranked(1, Some(4));
+        let expected = [(1, 5), (3, 5), (5, 5), (0, 3)];
+        assert_eq!(ranked, expected.map(|(gid, count)| Hit { gid, count }));
+    }
+
+    #[test]
+    fn ranked_drops_hits_under_min_score() {
+        let result = result();
+        for min_score in [1, 6, 12, 13] {
+            let expected = reference(&result, min_score);
+            assert_eq!(result.ranked(min_score, None), expected);
+            assert_eq!(result.ranked(min_score, Some(10)), expected[..expected.len().min(10)]);
+            assert!(expected.iter().all(|hit| hit.count >= min_score));
+        }
+        assert!(result.ranked(13, None).is_empty());
+    }
+}
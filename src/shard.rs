@@ -0,0 +1,159 @@
+use std::path::PathBuf;
+
+use crate::error::{Error, Result};
+use crate::index::Index;
+use crate::query::QueryResult;
+use crate::similarity::Estimator;
+use crate::sketcher::Sketch;
+use crate::width::IndexInt;
+
+/// The shards of an index partitioned by sketch position. A query is run on
+/// every shard and the per-genome counts are added up.
+pub struct ShardSet<G: IndexInt, P: IndexInt> {
+    shards: Vec<Index<G, P>>,
+}
+
+impl<G: IndexInt, P: IndexInt> ShardSet<G, P> {
+    pub fn load(paths: &[PathBuf]) -> Result<ShardSet<G, P>> {
+        let mut shards = Vec::with_capacity(paths.len());
+        for path in paths {
+            shards.push(Index::load(path)?);
+        }
+        ShardSet::new(shards)
+    }
+
+    /// Every shard must come from the same build and each one must be present once
+    pub fn new(mut shards: Vec<Index<G, P>>) -> Result<ShardSet<G, P>> {
+        if shards.is_empty() {
+            return Err(Error::InvalidParameters(String::from("no shard given")));
+        }
+        shards.sort_by_key(|shard| shard.get_shard().0);
+        let first = shards[0].header();
+        for (j, shard) in shards.iter().enumerate() {
+            let header = shard.header();
+            if (header.k, header.lf, header.w, header.e) != (first.k, first.lf, first.w, first.e)
+                || header.genome_numbers != first.genome_numbers
+            {
+                return Err(Error::InvalidParameters(format!(
+                    "shard {} was not built with the same parameters and genomes as shard {}",
+                    header.shard, first.shard
+                )));
+            }
+            if header.nb_shards as usize != shards.len() || header.shard as usize != j {
+                return Err(Error::InvalidParameters(format!(
+                    "got {} shard files but shard {} belongs to a set of {} shards, or is missing or duplicated",
+                    shards.len(),
+                    header.shard,
+                    header.nb_shards
+                )));
+            }
+            let first_index = &shards[0];
+            if let Some(gid) = (0..shard.get_nb_genomes()).find(|&gid| shard.get_name(gid) != first_index.get_name(gid)) {
+                return Err(Error::InvalidParameters(format!(
+                    "genome {} is '{}' in shard {} but '{}' in shard {}",
+                    gid,
+                    shard.get_name(gid).unwrap_or_default(),
+                    header.shard,
+                    first_index.get_name(gid).unwrap_or_default(),
+                    first.shard
+                )));
+            }
+        }
+        Ok(ShardSet { shards })
+    }
+
+    /// All the shards share the sketching parameters and the genome metadata
+    pub fn get_index(&self) -> &Index<G, P> {
+        &self.shards[0]
+    }
+
+    pub fn get_shards(&self) -> &[Index<G, P>] {
+        &self.shards
+    }
+
+    pub fn get_nb_genomes(&self) -> usize {
+        self.shards[0].get_nb_genomes()
+    }
+
+    /// Estimator for the summed counts, on every sketch position
+    pub fn estimator(&self) -> Estimator {
+        let params = self.get_index().params();
+        Estimator::new(params, params.sketch_size())
+    }
+
+    pub fn query_sketch(&self, sketch: &Sketch) -> Result<QueryResult> {
+        let mut result = QueryResult::new(vec![0; self.get_nb_genomes()]);
+        for shard in &self.shards {
+            result.merge(&shard.query_sketch(sketch)?);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::GenomeMetadata;
+    use crate::sketcher::Params;
+
+    const PARAMS: Params = Params { k: 15, lf: 8, w: 8, e: 1000 };
+
+    fn sketch(g: u64) -> Sketch {
+        Sketch::from_bins(
+            (0..256u64)
+                .map(|i| if (i * 37 + g * 11) % 9 >= g { (i * 7) % 256 } else { (i * 7 + g * 53) % 256 })
+                .collect(),
+        )
+    }
+
+    // Shard `shard` of `nb_shards`, or the whole index with None, of genomes
+    // named by `names`
+    fn index(shard: Option<(u32, u32)>, names: &[&str]) -> Index {
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        if let Some((shard, nb_shards)) = shard {
+            index.set_shard(shard, nb_shards).unwrap();
+        }
+        for (g, name) in names.iter().enumerate() {
+            index.insert_genome(&sketch(g as u64), GenomeMetadata::new(name, &[], 1000)).unwrap();
+        }
+        index
+    }
+
+    const NAMES: [&str; 6] = ["g0", "g1", "g2", "g3", "g4", "g5"];
+
+    #[test]
+    fn shards_answer_like_the_whole_index() {
+        let whole = index(None, &NAMES);
+        let shards = ShardSet::new((0..3).rev().map(|j| index(Some((j, 3)), &NAMES)).collect()).unwrap();
+        assert_eq!(shards.get_nb_genomes(), 6);
+        assert!(shards.get_shards().iter().enumerate().all(|(j, shard)| shard.get_shard() == (j as u32, 3)));
+        for g in 0..8 {
+            let query = sketch(g);
+            assert_eq!(shards.query_sketch(&query).unwrap(), whole.query_sketch(&query).unwrap(), "query {}", g);
+        }
+        let params = whole.params();
+        assert_eq!(shards.estimator().estimate(100, 1000, 1000), Estimator::new(params, params.sketch_size()).estimate(100, 1000, 1000));
+    }
+
+    #[test]
+    fn shards_must_name_the_same_genomes() {
+        let mut renamed = NAMES;
+        renamed[4] = "other";
+        let shards = vec![index(Some((0, 2)), &NAMES), index(Some((1, 2)), &renamed)];
+        match ShardSet::new(shards) {
+            Err(Error::InvalidParameters(message)) => assert_eq!(message, "genome 4 is 'other' in shard 1 but 'g4' in shard 0"),
+            _ => panic!("genomes of other names accepted"),
+        }
+        let shards = vec![index(Some((0, 2)), &NAMES), index(Some((1, 2)), &NAMES[..5])];
+        assert!(matches!(ShardSet::new(shards), Err(Error::InvalidParameters(_))));
+    }
+
+    #[test]
+    fn every_shard_must_be_given_once() {
+        let missing = vec![index(Some((0, 3)), &NAMES), index(Some((2, 3)), &NAMES)];
+        assert!(matches!(ShardSet::new(missing), Err(Error::InvalidParameters(_))));
+        let duplicated = vec![index(Some((0, 2)), &NAMES), index(Some((0, 2)), &NAMES)];
+        assert!(matches!(ShardSet::new(duplicated), Err(Error::InvalidParameters(_))));
+        assert!(matches!(ShardSet::<u32, u16>::new(Vec::new()), Err(Error::InvalidParameters(_))));
+    }
+}
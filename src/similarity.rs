@@ -0,0 +1,381 @@
+use crate::error::{Error, Result};
+use crate::sketcher::{Params, Sketch};
+
+/// Confidence level of the intervals unless told otherwise
+pub const DEFAULT_CONFIDENCE: f64 = 0.95;
+
+/// Similarity of a query to a genome, estimated from the number of sketch
+/// positions where they hold the same fingerprint
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Similarity {
+    pub shared: u32,      // positions with the same fingerprint
+    pub jaccard: f64,     // corrected for chance fingerprint collisions
+    pub containment: f64, // fraction of the k-mers of the query found in the genome
+    pub distance: f64,    // Mash distance
+    pub ani: f64,         // 1 - distance
+    pub pvalue: f64,      // probability of sharing at least as many positions by chance
+    // (low, high) bounds at the confidence level of the Estimator
+    pub jaccard_interval: (f64, f64),
+    pub distance_interval: (f64, f64),
+    pub ani_interval: (f64, f64),
+}
+
+impl Similarity {
+    /// Names of the fields given by values
+    pub const COLUMNS: [&'static str; 12] = [
+        "shared",
+        "jaccard",
+        "jaccard_low",
+        "jaccard_high",
+        "containment",
+        "distance",
+        "distance_low",
+        "distance_high",
+        "ani",
+        "ani_low",
+        "ani_high",
+        "pvalue",
+    ];
+
+    /// Fields formatted as numbers, in the order of COLUMNS
+    pub fn values(&self) -> [String; 12] {
+        [
+            self.shared.to_string(),
+            format!("{:.4}", self.jaccard),
+            format!("{:.4}", self.jaccard_interval.0),
+            format!("{:.4}", self.jaccard_interval.1),
+            format!("{:.4}", self.containment),
+            format!("{:.4}", self.distance),
+            format!("{:.4}", self.distance_interval.0),
+            format!("{:.4}", self.distance_interval.1),
+            format!("{:.4}", self.ani),
+            format!("{:.4}", self.ani_interval.0),
+            format!("{:.4}", self.ani_interval.1),
+            format!("{:.3e}", self.pvalue),
+        ]
+    }
+}
+
+/// Turns shared position counts into Similarity estimates for sketches made
+/// with the same parameters, compared on `positions` sketch positions
+#[derive(Clone, Copy, Debug)]
+pub struct Estimator {
+    k: u32,
+    e: f64,
+    fingerprint_range: f64,
+    positions: u32,
+    z: f64, // normal quantile of the confidence level
+}
+
+impl Estimator {
+    pub fn new(params: Params, positions: usize) -> Estimator {
+        Estimator {
+            k: params.k,
+            e: params.e as f64,
+            fingerprint_range: params.fingerprint_range() as f64,
+            positions: positions as u32,
+            z: normal_quantile(0.5 + DEFAULT_CONFIDENCE / 2.0),
+        }
+    }
+
+    /// Give the intervals at confidence `level`, between 0 and 1 excluded
+    pub fn with_confidence(mut self, level: f64) -> Result<Estimator> {
+        if !(level > 0.0 && level < 1.0) {
+            return Err(Error::InvalidParameters(format!(
+                "the confidence level must be between 0 and 1 excluded, not {}",
+                level
+            )));
+        }
+        self.z = normal_quantile(0.5 + level / 2.0);
+        Ok(self)
+    }
+
+    pub fn get_positions(&self) -> u32 {
+        self.positions
+    }
+
+    /// Probability that a position holds the same fingerprint in the sketches
+    /// of two unrelated genomes of `a` and `b` distinct k-mers.
+    /// A genome of n k-mers gets fingerprints v with P(fp < v) = 1-(1-v/R)^(n/E),
+    /// uniform for n = E, which gives 1/R. Fingerprints of genomes smaller
+    /// than E lean towards R-1, where they are clamped. An unknown size (0)
+    /// is taken as E.
+    pub fn chance_match(&self, a: u64, b: u64) -> f64 {
+        let range = self.fingerprint_range;
+        if a == 0 || b == 0 {
+            return 1.0 / range;
+        }
+        let a = a as f64 / self.e;
+        let b = b as f64 / self.e;
+        // density product integrated over [0, 1-1/R), plus the clamped last fingerprint
+        let s = a + b - 1.0;
+        let integral = if s.abs() < 1e-9 { range.ln() } else { (1.0 - range.powf(-s)) / s };
+        (a * b * integral / range + range.powf(-(a + b))).min(1.0)
+    }
+
+    /// Estimates for a query of `query_cardinality` k-mers sharing `shared`
+    /// positions with a genome of `genome_cardinality` k-mers
+    pub fn estimate(&self, shared: u32, query_cardinality: u64, genome_cardinality: u64) -> Similarity {
+        let chance = self.chance_match(query_cardinality, genome_cardinality);
+        let jaccard = self.jaccard(shared, query_cardinality, genome_cardinality);
+        let containment = containment(jaccard, query_cardinality, genome_cardinality);
+        let distance = mash_distance(jaccard, self.k);
+        // Every estimate is monotone in the matching fraction, the bounds
+        // of its interval give the bounds of theirs
+        let (low, high) = self.matching_interval(shared);
+        let jaccard_interval = (corrected_jaccard(low, chance), corrected_jaccard(high, chance));
+        let distance_interval = (
+            mash_distance(jaccard_interval.1, self.k),
+            mash_distance(jaccard_interval.0, self.k),
+        );
+        Similarity {
+            shared,
+            jaccard,
+            containment,
+            distance,
+            ani: 1.0 - distance,
+            pvalue: binomial_tail(self.positions, chance, shared),
+            jaccard_interval,
+            distance_interval,
+            ani_interval: (1.0 - distance_interval.1, 1.0 - distance_interval.0),
+        }
+    }
+
+    /// A position matches when the genomes share its minimal k-mer, with
+    /// probability J, or by chance otherwise, so the matching fraction m is
+    /// J + (1-J)p for a chance match probability p
+    pub fn jaccard(&self, shared: u32, query_cardinality: u64, genome_cardinality: u64) -> f64 {
+        if self.positions == 0 {
+            return 0.0;
+        }
+        let matching = shared as f64 / self.positions as f64;
+        corrected_jaccard(matching, self.chance_match(query_cardinality, genome_cardinality))
+    }
+
+    /// Wilson score interval of the matching fraction, each of the positions
+    /// being a Bernoulli trial
+    pub fn matching_interval(&self, shared: u32) -> (f64, f64) {
+        if self.positions == 0 {
+            return (0.0, 1.0);
+        }
+        let n = self.positions as f64;
+        let m = shared as f64 / n;
+        let z2 = self.z * self.z;
+        let center = (m + z2 / (2.0 * n)) / (1.0 + z2 / n);
+        let half = self.z / (1.0 + z2 / n) * (m * (1.0 - m) / n + z2 / (4.0 * n * n)).sqrt();
+        ((center - half).max(0.0), (center + half).min(1.0))
+    }
+}
+
+// Jaccard from the matching fraction, see Estimator::jaccard
+fn corrected_jaccard(matching: f64, chance: f64) -> f64 {
+    if chance >= 1.0 {
+        return 0.0;
+    }
+    ((matching - chance) / (1.0 - chance)).clamp(0.0, 1.0)
+}
+
+/// Number of positions holding the same fingerprint in two sketches,
+/// Sketch::EMPTY bins aside
+pub fn shared_positions(a: &[u64], b: &[u64]) -> u32 {
+    a.iter().zip(b).filter(|&(x, y)| x == y && *x != Sketch::EMPTY).count() as u32
+}
+
+/// Fraction of the k-mers of the query found in the genome, from
+/// |Q∩G| = J(|Q|+|G|)/(1+J). 0 for a query of unknown size.
+pub fn containment(jaccard: f64, query_cardinality: u64, genome_cardinality: u64) -> f64 {
+    if query_cardinality == 0 {
+        return 0.0;
+    }
+    let total = (query_cardinality + genome_cardinality) as f64;
+    (jaccard * total / (1.0 + jaccard) / query_cardinality as f64).min(1.0)
+}
+
+/// Mash distance ln((1+J)/2J)/k, 1 for genomes without any shared k-mer
+pub fn mash_distance(jaccard: f64, k: u32) -> f64 {
+    if jaccard <= 0.0 {
+        return 1.0;
+    }
+    (((1.0 + jaccard) / (2.0 * jaccard)).ln() / k as f64).clamp(0.0, 1.0)
+}
+
+/// P(X >= c) for X following a binomial law B(n, p): the probability for
+/// unrelated genomes to share at least c of n positions, each one matching
+/// by chance with probability p. Terms are summed from c outwards, relative
+/// to the first one, until they no longer change the sum.
+pub fn binomial_tail(n: u32, p: f64, c: u32) -> f64 {
+    if c == 0 || p >= 1.0 {
+        return 1.0;
+    }
+    if c > n || p <= 0.0 {
+        return 0.0;
+    }
+    let odds = p / (1.0 - p);
+    if c as f64 > n as f64 * p {
+        // upper tail, terms decrease from c
+        let (mut term, mut sum) = (1.0, 1.0);
+        for i in c..n {
+            term *= (n - i) as f64 / (i + 1) as f64 * odds;
+            sum += term;
+            if term < sum * 1e-17 {
+                break;
+            }
+        }
+        (ln_binomial_term(n, p, c) + sum.ln()).exp().min(1.0)
+    } else {
+        // one minus the lower tail, terms decrease from c-1
+        let (mut term, mut sum) = (1.0, 1.0);
+        for i in (1..c).rev() {
+            term *= i as f64 / (n - i + 1) as f64 / odds;
+            sum += term;
+            if term < sum * 1e-17 {
+                break;
+            }
+        }
+        (1.0 - (ln_binomial_term(n, p, c - 1) + sum.ln()).exp()).max(0.0)
+    }
+}
+
+// ln P(X = i) for X following B(n, p)
+fn ln_binomial_term(n: u32, p: f64, i: u32) -> f64 {
+    let (n, i) = (n as f64, i as f64);
+    ln_gamma(n + 1.0) - ln_gamma(i + 1.0) - ln_gamma(n - i + 1.0) + i * p.ln() + (n - i) * (-p).ln_1p()
+}
+
+// Lanczos approximation (g = 7, 9 terms), for x > 0
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection formula
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, &coefficient) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += coefficient / (x + i as f64);
+    }
+    let t = x + 7.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+/// Quantile of the standard normal law, for 0 < p < 1 (Acklam's rational
+/// approximation, relative error below 1.2e-9)
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const LOW: f64 = 0.02425;
+    if p < LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p > 1.0 - LOW {
+        -normal_quantile(1.0 - p)
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_relative(value: f64, expected: f64, tolerance: f64) {
+        assert!(
+            ((value - expected) / expected).abs() < tolerance,
+            "{} instead of {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn binomial_tail_known_values() {
+        // B(1024, 1/256), the chance matches of 1024 positions at w = 8,
+        // exact values computed with rational arithmetic
+        let p = 1.0 / 256.0;
+        assert_relative(binomial_tail(1024, p, 1), 0.9818272653594924, 1e-9);
+        assert_relative(binomial_tail(1024, p, 4), 0.5669122651260211, 1e-9);
+        assert_relative(binomial_tail(1024, p, 10), 0.008003353265472189, 1e-9);
+        assert_relative(binomial_tail(1024, p, 20), 9.047662961115337e-9, 1e-9);
+    }
+
+    #[test]
+    fn binomial_tail_edges() {
+        assert_eq!(binomial_tail(1024, 0.01, 0), 1.0);
+        assert_eq!(binomial_tail(1024, 0.01, 1025), 0.0);
+        assert_eq!(binomial_tail(1024, 0.0, 1), 0.0);
+        assert_eq!(binomial_tail(1024, 1.0, 1024), 1.0);
+        assert_relative(binomial_tail(1024, 1e-300, 1), 1024e-300, 1e-6);
+        assert_relative(binomial_tail(1024, 0.5, 1024), 0.5f64.powi(1024), 1e-9);
+    }
+
+    #[test]
+    fn ln_gamma_known_values() {
+        assert_relative(ln_gamma(0.5), 0.5723649429247004, 1e-12);
+        assert_relative(ln_gamma(10.0), 12.801827480081467, 1e-12);
+        assert_relative(ln_gamma(100.5), 361.4355404677776, 1e-12);
+        assert!(ln_gamma(1.0).abs() < 1e-12 && ln_gamma(2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn normal_quantile_known_values() {
+        assert_relative(normal_quantile(0.975), 1.959963984540054, 1e-8);
+        assert_relative(normal_quantile(0.995), 2.5758293035489, 1e-8);
+        assert_relative(normal_quantile(0.01), -2.3263478740408408, 1e-8);
+        assert_eq!(normal_quantile(0.5), 0.0);
+    }
+
+    #[test]
+    fn wilson_interval() {
+        // 30 matches out of 100 at 95%
+        let estimator = Estimator::new(Params::default(), 100);
+        let (low, high) = estimator.matching_interval(30);
+        assert_relative(low, 0.2189488529493276, 1e-7);
+        assert_relative(high, 0.3958485463334666, 1e-7);
+
+        let (low, high) = estimator.matching_interval(0);
+        assert_eq!(low, 0.0);
+        assert!(high > 0.0 && high < 0.05);
+        assert_eq!(Estimator::new(Params::default(), 0).matching_interval(0), (0.0, 1.0));
+    }
+}
@@ -0,0 +1,195 @@
+use std::io::{self, Read, Write};
+
+use crate::index::Gid;
+use crate::storage::{self, Remaining};
+
+/// The sketches of the indexed genomes, bit-packed to w bits per bin. Every
+/// genome starts on a fresh u64 so that a genome can be written on its own.
+/// A genome without any k-mer has no fingerprint to store and is only flagged.
+pub struct SketchStore {
+    w: u32,
+    bins: usize,           // bins stored per genome
+    words_per_genome: usize,
+    words: Vec<u64>,
+    empty: Vec<bool>,
+}
+
+impl SketchStore {
+    pub fn new(w: u32, bins: usize) -> SketchStore {
+        SketchStore {
+            w,
+            bins,
+            words_per_genome: SketchStore::words_per_genome(w, bins),
+            words: Vec::new(),
+            empty: Vec::new(),
+        }
+    }
+
+    pub fn words_per_genome(w: u32, bins: usize) -> usize {
+        (bins * w as usize).div_ceil(64)
+    }
+
+    fn len(&self) -> usize {
+        self.empty.len()
+    }
+
+    pub fn get_bins(&self) -> usize {
+        self.bins
+    }
+
+    pub fn get_bytes(&self) -> usize {
+        self.words.capacity() * std::mem::size_of::<u64>() + self.empty.capacity()
+    }
+
+    /// `sketch` holds the `bins` fingerprints to store, u64::MAX for an empty sketch
+    pub fn pack(w: u32, sketch: &[u64]) -> (Vec<u64>, bool) {
+        let mut words = vec![0u64; SketchStore::words_per_genome(w, sketch.len())];
+        if sketch.iter().all(|&val| val == u64::MAX) {
+            return (words, true);
+        }
+        let mask = mask(w);
+        for (i, &val) in sketch.iter().enumerate() {
+            let bit = i * w as usize;
+            let (word, shift) = (bit / 64, bit % 64);
+            let val = val & mask;
+            words[word] |= val << shift;
+            if shift + w as usize > 64 {
+                words[word + 1] |= val >> (64 - shift);
+            }
+        }
+        (words, false)
+    }
+
+    pub fn insert(&mut self, gid: Gid, sketch: &[u64]) {
+        assert_eq!(sketch.len(), self.bins, "sketch of {} bins in a store of {} bins", sketch.len(), self.bins);
+        if gid >= self.len() {
+            self.empty.resize(gid + 1, true);
+            self.words.resize((gid + 1) * self.words_per_genome, 0);
+        }
+        let (packed, empty) = SketchStore::pack(self.w, sketch);
+        let start = gid * self.words_per_genome;
+        self.words[start..start + self.words_per_genome].copy_from_slice(&packed);
+        self.empty[gid] = empty;
+    }
+
+    /// Whether genome `gid` is stored with the fingerprints of `sketch`, as
+    /// packed by pack
+    pub fn holds(&self, gid: Gid, sketch: &[u64]) -> bool {
+        let (packed, empty) = SketchStore::pack(self.w, sketch);
+        let start = gid * self.words_per_genome;
+        self.empty[gid] == empty && self.words[start..start + self.words_per_genome] == packed[..]
+    }
+
+    pub fn get_bin(&self, gid: Gid, i: usize) -> u64 {
+        if self.empty[gid] {
+            return u64::MAX;
+        }
+        let bit = i * self.w as usize;
+        let (word, shift) = (gid * self.words_per_genome + bit / 64, bit % 64);
+        let mut val = self.words[word] >> shift;
+        if shift + self.w as usize > 64 {
+            val |= self.words[word + 1] << (64 - shift);
+        }
+        val & mask(self.w)
+    }
+
+    pub fn get(&self, gid: Gid) -> Vec<u64> {
+        (0..self.bins).map(|i| self.get_bin(gid, i)).collect()
+    }
+
+    /// Layout: one empty flag byte per genome, then the packed words
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for &empty in &self.empty {
+            writer.write_all(&[empty as u8])?;
+        }
+        for &word in &self.words {
+            storage::write_u64(writer, word)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read + Remaining>(reader: &mut R, w: u32, bins: usize, nb_genomes: u64) -> io::Result<SketchStore> {
+        let mut store = SketchStore::new(w, bins);
+        let genome_bytes = 1 + 8 * store.words_per_genome as u64;
+        let nb_genomes = storage::check_count(reader, nb_genomes, genome_bytes, "genome sketches")?;
+        let flags = storage::read_bytes(reader, nb_genomes)?;
+        store.empty = flags.iter().map(|&flag| flag != 0).collect();
+        store.words = storage::read_items(reader, nb_genomes * store.words_per_genome, |reader| storage::read_u64(reader))?;
+        Ok(store)
+    }
+}
+
+fn mask(w: u32) -> u64 {
+    if w >= 64 {
+        !0
+    } else {
+        (1u64 << w) - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    // Values using all w bits, so that bins spanning two words are checked
+    fn sketch(w: u32, bins: usize) -> Vec<u64> {
+        (0..bins as u64)
+            .map(|i| i.wrapping_mul(0x9E3779B97F4A7C15).rotate_left(17) & mask(w))
+            .collect()
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        for w in [12, 13, 32] {
+            let bins = 150;
+            let mut store = SketchStore::new(w, bins);
+            let sketches: Vec<Vec<u64>> = (0..3).map(|gid| sketch(w, bins).into_iter().map(|val| val ^ gid).collect()).collect();
+            for (gid, sketch) in sketches.iter().enumerate() {
+                store.insert(gid, sketch);
+            }
+            for (gid, sketch) in sketches.iter().enumerate() {
+                assert_eq!(&store.get(gid), sketch, "w = {}", w);
+            }
+            assert_eq!(store.get_bin(1, bins - 1), sketches[1][bins - 1]);
+        }
+    }
+
+    #[test]
+    fn empty_sketch() {
+        let mut store = SketchStore::new(13, 10);
+        store.insert(1, &[u64::MAX; 10]);
+        store.insert(0, &sketch(13, 10));
+        assert_eq!(store.get(1), vec![u64::MAX; 10]);
+        assert_eq!(store.get(0), sketch(13, 10));
+    }
+
+    #[test]
+    fn holds_compares_every_bin() {
+        let mut store = SketchStore::new(13, 10);
+        store.insert(0, &sketch(13, 10));
+        store.insert(1, &[u64::MAX; 10]);
+        let mut other = sketch(13, 10);
+        other[9] ^= 1;
+        assert!(store.holds(0, &sketch(13, 10)));
+        assert!(!store.holds(0, &other));
+        assert!(!store.holds(0, &[u64::MAX; 10]));
+        assert!(store.holds(1, &[u64::MAX; 10]));
+        assert!(!store.holds(1, &sketch(13, 10)));
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut store = SketchStore::new(13, 100);
+        store.insert(0, &sketch(13, 100));
+        store.insert(1, &[u64::MAX; 100]);
+        let mut bytes = Vec::new();
+        store.write(&mut bytes).unwrap();
+        let len = bytes.len() as u64;
+        let read = SketchStore::read(&mut bytes.as_slice().take(len), 13, 100, 2).unwrap();
+        assert_eq!(read.get(0), store.get(0));
+        assert_eq!(read.get(1), store.get(1));
+        assert!(SketchStore::read(&mut bytes.as_slice().take(len), 13, 100, 3).is_err());
+    }
+}
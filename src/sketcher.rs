@@ -0,0 +1,434 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::inputs::IssueKind;
+use crate::metadata::GenomeMetadata;
+
+/// Sketching parameters, shared by an index and the sketches of its queries.
+/// Laid out as onika_params for the C interface.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    /// k-mer size, from 1 to 31
+    pub k: u32,
+    /// log2 of the number of bins of a sketch
+    pub lf: u32,
+    /// fingerprint size in bits, the index has 2^w buckets
+    pub w: u32,
+    /// expected genome size, makes the fingerprints of such genomes uniform
+    pub e: u32,
+}
+
+impl Default for Params {
+    fn default() -> Params {
+        Params {
+            k: 31,
+            lf: 15,
+            w: 12,
+            e: 5000000,
+        }
+    }
+}
+
+impl Params {
+    /// Number of bins of a sketch
+    pub fn sketch_size(&self) -> usize {
+        1 << self.lf
+    }
+
+    /// Number of distinct fingerprints, 2^w
+    pub fn fingerprint_range(&self) -> u64 {
+        1 << self.w
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.k == 0 || self.k > 31 {
+            return Err(Error::InvalidParameters(format!("k-mer size must be between 1 and 31, not {}", self.k)));
+        }
+        if self.lf == 0 || self.lf > 31 {
+            return Err(Error::InvalidParameters(format!("log2 of the sketch size must be between 1 and 31, not {}", self.lf)));
+        }
+        if self.w == 0 || self.w > 32 {
+            return Err(Error::InvalidParameters(format!("fingerprint size must be between 1 and 32, not {}", self.w)));
+        }
+        Ok(())
+    }
+}
+
+/// Sketch of a genome: the fingerprint of each of its bins. Every bin of a
+/// genome without any k-mer holds `Sketch::EMPTY`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sketch {
+    bins: Vec<u64>,
+}
+
+impl Sketch {
+    pub const EMPTY: u64 = u64::MAX;
+
+    pub fn from_bins(bins: Vec<u64>) -> Sketch {
+        Sketch { bins }
+    }
+
+    pub fn bins(&self) -> &[u64] {
+        &self.bins
+    }
+
+    pub fn into_bins(self) -> Vec<u64> {
+        self.bins
+    }
+
+    pub fn nb_bins(&self) -> usize {
+        self.bins.len()
+    }
+}
+
+/// Turns genomes into One Permutation Hashing sketches: the minimal hash of
+/// the k-mers of each bin, densified, then mapped to a w-bit fingerprint
+pub struct Sketcher {
+    k: u32,                         // kmer size
+    f: u32,                         // number of bins
+    w: u32,                         // fingerprint size
+    e: u32,                         // Expected genome size (5000000)
+    fingerprint_range: u64,         // 2^w
+    maximal_remainder: u32,         // 2^H-1
+    lf: u32,                        // log2(F)
+    mask_fingerprint: u64,          // 2^(64-lf)-1
+    mi: u64,                        // -1
+    offset_update_kmer: u64,
+}
+
+impl Sketcher {
+    pub fn new(params: Params) -> Result<Sketcher> {
+        params.check()?;
+        let Params { k, lf, w, e } = params;
+        Ok(Sketcher {
+            k,
+            f: 1u32 << lf,
+            w,
+            e,
+            fingerprint_range: 1u64 << w,
+            maximal_remainder: ((1u64 << w) - 1) as u32,
+            lf,
+            mask_fingerprint: (1u64 << (64 - lf)) - 1,
+            mi: Sketch::EMPTY,
+            offset_update_kmer: 1u64 << (2 * k),
+        })
+    }
+
+    pub fn params(&self) -> Params {
+        Params {
+            k: self.k,
+            lf: self.lf,
+            w: self.w,
+            e: self.e,
+        }
+    }
+
+    fn exists_test(&self, name: &str) -> bool {
+        Path::new(name).exists()
+    }
+
+
+    fn get_data_type(&self, filename: &str) -> char {
+        if filename.contains(".fq") {
+            return 'Q';
+        }
+        if filename.contains(".fastq") {
+            return 'Q';
+        }
+        'A'
+    }
+
+
+    /// Sketch of an input genome, or why it cannot be indexed
+    pub fn sketch_input(&self, filename: &str) -> std::result::Result<(Sketch, GenomeMetadata), (IssueKind, String)> {
+        if !self.exists_test(filename) {
+            return Err((IssueKind::Missing, String::from("no such file")));
+        }
+        let (sketch, metadata) = self
+            .sketch_genome(filename)
+            .map_err(|e| (IssueKind::Unreadable, e.to_string()))?;
+        if metadata.total_bases == 0 {
+            return Err((IssueKind::Empty, format!("{} sequences without any base", metadata.nb_sequences)));
+        }
+        if metadata.cardinality == 0 {
+            return Err((
+                IssueKind::TooShort,
+                format!("no {}-mer in {} bases", self.k, metadata.total_bases),
+            ));
+        }
+        Ok((sketch, metadata))
+    }
+
+
+    /// Sketch every sequence of a FASTA/FASTQ file into a single genome sketch
+    pub fn sketch_file(&self, filestr: &str) -> Result<Sketch> {
+        self.sketch_genome(filestr).map(|(sketch, _)| sketch)
+    }
+
+    /// Sketch of a genome file along with its metadata
+    pub fn sketch_genome(&self, filestr: &str) -> Result<(Sketch, GenomeMetadata)> {
+        let sequences = self.read_sequences(filestr)?;
+        self.sketch_records(filestr, &sequences)
+    }
+
+    /// Sketch and metadata of the records of a genome named `name`
+    pub fn sketch_records(&self, name: &str, sequences: &[String]) -> Result<(Sketch, GenomeMetadata)> {
+        let mut bins = vec![self.mi; self.f as usize];
+        for sequence in sequences {
+            self.compute_sketch(sequence, &mut bins)?;
+        }
+        let cardinality = self.estimate_cardinality(&bins);
+        self.finish_sketch(&mut bins);
+        Ok((Sketch::from_bins(bins), GenomeMetadata::new(name, sequences, cardinality)))
+    }
+
+    /// Sketch of sequences held in memory, as if they were the records of one file
+    pub fn sketch_sequences<S: AsRef<str>>(&self, sequences: &[S]) -> Result<Sketch> {
+        let mut bins = vec![self.mi; self.f as usize];
+        for sequence in sequences {
+            self.compute_sketch(sequence.as_ref(), &mut bins)?;
+        }
+        self.finish_sketch(&mut bins);
+        Ok(Sketch::from_bins(bins))
+    }
+
+    /// Number of distinct k-mers estimated from a sketch before densification:
+    /// linear counting while some bins are empty, otherwise from the mean
+    /// minimal hash of a bin, which is 1/(n/F + 1) for n k-mers
+    pub fn estimate_cardinality(&self, sketch: &[u64]) -> u64 {
+        let f = sketch.len() as f64;
+        let empty_cell = sketch.iter().filter(|&&val| val == self.mi).count();
+        if empty_cell == sketch.len() {
+            return 0;
+        }
+        if empty_cell > 0 {
+            return (f * (f / empty_cell as f64).ln()).round() as u64;
+        }
+        let mean = sketch.iter().map(|&val| val as f64 / u64::MAX as f64).sum::<f64>() / f;
+        (f * (1.0 / mean - 1.0)).max(0.0).round() as u64
+    }
+
+    /// Keep, for each bin, the minimal hash of the k-mers of `reference`.
+    /// Several sequences can be accumulated before calling finish_sketch.
+    /// A sequence shorter than k adds nothing.
+    pub fn compute_sketch(&self, reference: &str, sketch: &mut Vec<u64>) -> Result<()> {
+        if sketch.len() != self.f as usize {
+            *sketch = vec![self.mi; self.f as usize];
+        }
+        if !reference.is_ascii() {
+            return Err(Error::InvalidSequence(String::from("non ASCII character")));
+        }
+        let k = self.k as usize;
+        if reference.len() < k {
+            return Ok(());
+        }
+        let bytes = reference.as_bytes();
+        let mut s_kmer = self.str2numstrand(&reference[0..(k - 1)]);
+        let mut rc_kmer = self.rcb(s_kmer);
+
+        for i in 0..=(reference.len() - k) {
+            let nuc = bytes[i + k - 1] as char;
+            self.update_kmer(&mut s_kmer, nuc);
+            self.update_kmer_rc(&mut rc_kmer, nuc);
+
+            let canon = s_kmer.min(rc_kmer);
+            let hashed = self.revhash64(canon);
+            let bucket_id = self.unrevhash64(canon) >> (64 - self.lf);
+            let fp = hashed;
+
+            if sketch[bucket_id as usize] > fp {
+                sketch[bucket_id as usize] = fp;
+            }
+        }
+        Ok(())
+    }
+
+    /// Densify the empty bins then turn the minimal hashes into fingerprints
+    pub fn finish_sketch(&self, sketch: &mut [u64]) {
+        let empty_cell = sketch.iter().filter(|&&val| val == self.mi).count() as u32;
+        self.sketch_densification(sketch, empty_cell);
+
+        for val in sketch.iter_mut() {
+            if *val != self.mi {
+                *val = self.get_perfect_fingerprint(*val);
+            }
+        }
+    }
+
+    /// Read the sequences of a FASTA (multi-line) or FASTQ file
+    pub fn read_sequences(&self, filestr: &str) -> io::Result<Vec<String>> {
+        let file = File::open(filestr)?;
+        let reader = BufReader::new(file);
+
+        if self.get_data_type(filestr) == 'Q' {
+            let mut sequences = Vec::new();
+            for (i, line) in reader.lines().enumerate() {
+                let line = line?;
+                if i % 4 == 1 {
+                    sequences.push(line);
+                }
+            }
+            return Ok(sequences);
+        }
+
+        read_fasta(reader)
+    }
+
+
+    fn rcb(&self, mut min: u64) -> u64 {
+        let mut res = 0;
+        let mut offset = 1;
+        offset <<= 2 * self.k - 2;
+        for _ in 0..self.k {
+            res += (3 - (min % 4)) * offset;
+            min >>= 2;
+            offset >>= 2;
+        }
+        res
+    }
+
+    fn nuc2int(&self, c: char) -> u64 {
+        match c {
+            'C' => 1,
+            'G' => 2,
+            'T' => 3,
+            _ => 0,
+        }
+    }
+
+    fn nuc2intrc(&self, c: char) -> u64 {
+        match c {
+            'A' => 3,
+            'C' => 2,
+            'G' => 1,
+            _ => 0,
+        }
+    }
+
+    fn str2numstrand(&self, str: &str) -> u64 {
+        let mut res = 0;
+        for c in str.chars() {
+            res <<= 2;
+            res += match c {
+                'A' | 'a' => 0,
+                'C' | 'c' => 1,
+                'G' | 'g' => 2,
+                'T' | 't' => 3,
+                _ => return 0,
+            };
+        }
+        res
+    }
+
+    fn revhash64(&self, mut x: u64) -> u64 {
+        x = ((x >> 32) ^ x).wrapping_mul(0xD6E8FEB86659FD93);
+        x = ((x >> 32) ^ x).wrapping_mul(0xD6E8FEB86659FD93);
+        (x >> 32) ^ x
+    }
+
+    fn unrevhash64(&self, mut x: u64) -> u64 {
+        x = ((x >> 32) ^ x).wrapping_mul(0xCFEE444D8B59A89B);
+        x = ((x >> 32) ^ x).wrapping_mul(0xCFEE444D8B59A89B);
+        (x >> 32) ^ x
+    }
+
+    fn update_kmer(&self, min: &mut u64, nuc: char) {
+        *min <<= 2;
+        *min += self.nuc2int(nuc);
+        *min %= self.offset_update_kmer;
+    }
+
+    fn update_kmer_rc(&self, min: &mut u64, nuc: char) {
+        *min >>= 2;
+        *min += self.nuc2intrc(nuc) << (2 * self.k - 2);
+    }
+
+    fn hash_family(&self, x: u64, factor: u64) -> u64 {
+        self.unrevhash64(x).wrapping_add(factor.wrapping_mul(self.revhash64(x)))
+    }
+    // The minimum of E/F uniform hashes is mapped through its CDF so that
+    // fingerprints are uniform over [0, 2^w)
+    fn get_perfect_fingerprint(&self, hashed: u64) -> u64 {
+        let x = (hashed >> self.lf) as f64 / self.mask_fingerprint as f64;
+        let mut frac = (1.0 - x).powf(self.e as f64 / self.f as f64);
+        frac = 1.0 - frac;
+        ((self.fingerprint_range as f64 * frac) as u64).min(self.maximal_remainder as u64)
+    }
+
+
+    fn sketch_densification(&self, sketch: &mut [u64], mut empty_cell: u32) {
+        let size = sketch.len();
+        if empty_cell == 0 || empty_cell as usize == size {
+            return;
+        }
+        let mut step = 0;
+
+        while empty_cell != 0 {
+            for i in 0..size {
+                if sketch[i] != self.mi {
+                    let hash = self.hash_family(i as u64, step) % size as u64;
+                    if sketch[hash as usize] == self.mi {
+                        sketch[hash as usize] = sketch[i];
+                        empty_cell -= 1;
+                        if empty_cell == 0 {
+                            return;
+                        }
+                    }
+                }
+            }
+            step += 1;
+        }
+    }
+}
+
+/// Records of FASTA (multi-line) text. Lines before the first header form a
+/// record of their own, so a bare sequence is read as one record.
+pub fn read_fasta<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut sequences = Vec::new();
+    let mut current = String::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('>') {
+            if !current.is_empty() {
+                sequences.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push_str(line.trim_end());
+        }
+    }
+    if !current.is_empty() {
+        sequences.push(current);
+    }
+    Ok(sequences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_bins(sketcher: &Sketcher, sequences: &[&str]) -> Vec<u64> {
+        let mut bins = Vec::new();
+        for sequence in sequences {
+            sketcher.compute_sketch(sequence, &mut bins).unwrap();
+        }
+        bins
+    }
+
+    #[test]
+    fn every_kmer_of_a_sequence_is_sketched() {
+        let sketcher = Sketcher::new(Params { k: 5, lf: 10, w: 8, e: 1000 }).unwrap();
+        let filled = |bins: &[u64]| bins.iter().filter(|&&val| val != u64::MAX).count();
+        assert_eq!(filled(&raw_bins(&sketcher, &["ACGT"])), 0);
+        assert_eq!(filled(&raw_bins(&sketcher, &["ACGTA"])), 1);
+
+        // The sequence holds its k-mers, the first and last ones included
+        let sequence = "ACGTTGCAAGGCTTACGATCCAGT";
+        let kmers: Vec<&str> = (0..=sequence.len() - 5).map(|i| &sequence[i..i + 5]).collect();
+        assert_eq!(raw_bins(&sketcher, &[sequence]), raw_bins(&sketcher, &kmers));
+        assert_ne!(raw_bins(&sketcher, &[sequence]), raw_bins(&sketcher, &kmers[..kmers.len() - 1]));
+        assert_ne!(raw_bins(&sketcher, &[sequence]), raw_bins(&sketcher, &kmers[1..]));
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::index::Index;
+use crate::width::IndexInt;
+
+/// Occupancy figures of an index, used to tune W and S on real data
+pub struct IndexStats {
+    pub k: u32,
+    pub f: u32,
+    pub w: u32,
+    pub e: u32,
+    pub shard: (u32, u32),
+    pub nb_genomes: usize,
+    pub id_bits: u32,
+    pub pos_bits: u32,
+    pub nb_buckets: u64,
+    pub empty_buckets: u64,
+    pub total_postings: u64,
+    pub mean_posting_length: f64,
+    pub max_posting_length: u64,
+    // (smallest length, largest length, number of buckets), power of two classes
+    pub occupancy_histogram: Vec<(u64, u64, u64)>,
+    pub buckets_bytes: usize,
+    pub buckets_pos_bytes: usize,
+    pub sketches_bytes: usize,
+    // Probability that two unrelated genomes share the fingerprint of a
+    // position, from the observed fingerprint frequencies, and its value
+    // for perfectly uniform fingerprints (2^-w)
+    pub collision_rate: f64,
+    pub uniform_collision_rate: f64,
+}
+
+impl IndexStats {
+    pub fn new<G: IndexInt, P: IndexInt>(index: &Index<G, P>) -> IndexStats {
+        let params = index.params();
+        let nb_buckets = params.fingerprint_range();
+        let mut lengths = Vec::with_capacity(nb_buckets as usize);
+        for fingerprint in 0..nb_buckets {
+            lengths.push(index.get_bucket(fingerprint).map_or(0, |(genomes, _)| genomes.len() as u64));
+        }
+
+        let total_postings: u64 = lengths.iter().sum();
+        let max_posting_length = lengths.iter().copied().max().unwrap_or(0);
+        let empty_buckets = lengths.iter().filter(|&&len| len == 0).count() as u64;
+
+        let mut occupancy_histogram = vec![(0, 0, 0)];
+        for &len in &lengths {
+            // class 0 holds the empty buckets, class c the lengths in [2^(c-1), 2^c)
+            let class = (64 - len.leading_zeros()) as usize;
+            while occupancy_histogram.len() <= class {
+                let c = occupancy_histogram.len() as u32;
+                occupancy_histogram.push((1u64 << (c - 1), (1u64 << c) - 1, 0));
+            }
+            occupancy_histogram[class].2 += 1;
+        }
+
+        let collision_rate = if total_postings == 0 {
+            0.0
+        } else {
+            lengths
+                .iter()
+                .map(|&len| {
+                    let freq = len as f64 / total_postings as f64;
+                    freq * freq
+                })
+                .sum()
+        };
+
+        let (buckets_bytes, buckets_pos_bytes) = index.get_buckets_bytes();
+        IndexStats {
+            k: params.k,
+            f: params.sketch_size() as u32,
+            w: params.w,
+            e: params.e,
+            shard: index.get_shard(),
+            nb_genomes: index.get_nb_genomes(),
+            id_bits: G::BYTES * 8,
+            pos_bits: P::BYTES * 8,
+            nb_buckets,
+            empty_buckets,
+            total_postings,
+            mean_posting_length: total_postings as f64 / nb_buckets as f64,
+            max_posting_length,
+            occupancy_histogram,
+            buckets_bytes,
+            buckets_pos_bytes,
+            sketches_bytes: index.get_sketches_bytes(),
+            collision_rate,
+            uniform_collision_rate: 1.0 / nb_buckets as f64,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let histogram: Vec<String> = self
+            .occupancy_histogram
+            .iter()
+            .map(|&(low, high, count)| format!("{{\"min\":{},\"max\":{},\"buckets\":{}}}", low, high, count))
+            .collect();
+        format!(
+            "{{\"k\":{},\"s\":{},\"w\":{},\"e\":{},\"shard\":{},\"nb_shards\":{},\"genomes\":{},\"id_bits\":{},\"pos_bits\":{},\"buckets\":{},\"empty_buckets\":{},\"total_postings\":{},\"mean_posting_length\":{},\"max_posting_length\":{},\"buckets_bytes\":{},\"buckets_pos_bytes\":{},\"sketches_bytes\":{},\"collision_rate\":{},\"uniform_collision_rate\":{},\"occupancy_histogram\":[{}]}}",
+            self.k,
+            self.f,
+            self.w,
+            self.e,
+            self.shard.0,
+            self.shard.1,
+            self.nb_genomes,
+            self.id_bits,
+            self.pos_bits,
+            self.nb_buckets,
+            self.empty_buckets,
+            self.total_postings,
+            self.mean_posting_length,
+            self.max_posting_length,
+            self.buckets_bytes,
+            self.buckets_pos_bytes,
+            self.sketches_bytes,
+            self.collision_rate,
+            self.uniform_collision_rate,
+            histogram.join(",")
+        )
+    }
+}
+
+/// Table of the statistics and of the bucket occupancy histogram
+impl fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "+-------------------------------------------------------------------+")?;
+        writeln!(f, "|                              Statistics                           |")?;
+        writeln!(f, "+-----------------------------------+-------------------------------+")?;
+        writeln!(f, "| Buckets                           |{:>30} |", self.nb_buckets)?;
+        writeln!(f, "| Empty buckets                     |{:>30} |", self.empty_buckets)?;
+        writeln!(f, "| Total postings                    |{:>30} |", self.total_postings)?;
+        writeln!(f, "| Mean posting list length          |{:>30.2} |", self.mean_posting_length)?;
+        writeln!(f, "| Max posting list length           |{:>30} |", self.max_posting_length)?;
+        writeln!(f, "| Genome id / position widths       |{:>30} |", format!("{} / {} bits", self.id_bits, self.pos_bits))?;
+        writeln!(f, "| Bytes used by buckets             |{:>30} |", self.buckets_bytes)?;
+        writeln!(f, "| Bytes used by buckets_pos         |{:>30} |", self.buckets_pos_bytes)?;
+        writeln!(f, "| Bytes used by sketches            |{:>30} |", self.sketches_bytes)?;
+        writeln!(f, "| Fingerprint collision rate        |{:>30.6e} |", self.collision_rate)?;
+        writeln!(f, "| Uniform collision rate (2^-W)     |{:>30.6e} |", self.uniform_collision_rate)?;
+        writeln!(f, "+-----------------------------------+-------------------------------+")?;
+        writeln!(f, "|                    Bucket occupancy histogram                     |")?;
+        writeln!(f, "+-----------------------------------+-------------------------------+")?;
+        for &(low, high, count) in &self.occupancy_histogram {
+            let label = if low == high { format!("{}", low) } else { format!("{}-{}", low, high) };
+            writeln!(f, "| {:<33} |{:>30} |", label, count)?;
+        }
+        writeln!(f, "+-------------------------------------------------------------------+")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use crate::width::{self, IndexInt};
+
+/// On-disk layout of a persisted index (little endian):
+///   magic, version, header fields (a sharded index only holds the postings
+///   of the sketch positions of its shard),
+///   then for every fingerprint in 0..2^w: n, n genome ids, n sketch positions,
+///   stored on id_bytes and pos_bytes bytes,
+///   then the sketch of every genome (see SketchStore::write),
+///   then the metadata of every genome (see GenomeMetadata::write).
+/// Each of these four sections is followed by the FNV-1a checksum of its bytes.
+pub const MAGIC: &[u8; 8] = b"ONIKAIDX";
+pub const VERSION: u32 = 6;
+
+// Items read at once when a count comes from the file, so that a corrupt
+// count does not allocate more than the file holds
+const READ_CHUNK: usize = 1 << 16;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Reader or writer keeping the checksum of the bytes going through it
+pub struct Checksummed<T> {
+    inner: T,
+    hash: u64,
+}
+
+impl<T> Checksummed<T> {
+    pub fn new(inner: T) -> Checksummed<T> {
+        Checksummed { inner, hash: FNV_OFFSET }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.hash ^= byte as u64;
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    /// Checksum of the bytes since the previous call
+    pub fn take_checksum(&mut self) -> u64 {
+        std::mem::replace(&mut self.hash, FNV_OFFSET)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Readers knowing how many bytes are left, to check the counts read from a
+/// file against what it can still hold
+pub trait Remaining {
+    fn remaining(&self) -> u64;
+}
+
+impl<R: Read> Remaining for io::Take<R> {
+    fn remaining(&self) -> u64 {
+        self.limit()
+    }
+}
+
+impl<R: Remaining> Remaining for Checksummed<R> {
+    fn remaining(&self) -> u64 {
+        self.inner.remaining()
+    }
+}
+
+/// Checksummed reader of a whole index file
+pub fn open_index(path: &Path) -> io::Result<Checksummed<io::Take<BufReader<File>>>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    Ok(Checksummed::new(BufReader::new(file).take(len)))
+}
+
+/// `count` items of at least `item_bytes` bytes each must fit in the rest of
+/// the file
+pub fn check_count<R: Remaining>(reader: &R, count: u64, item_bytes: u64, what: &str) -> io::Result<usize> {
+    let fits = count.checked_mul(item_bytes).is_some_and(|bytes| bytes <= reader.remaining());
+    if !fits || usize::try_from(count).is_err() {
+        return Err(invalid_data(format!(
+            "{} {} exceed the {} bytes left in the file",
+            count,
+            what,
+            reader.remaining()
+        )));
+    }
+    Ok(count as usize)
+}
+
+/// `count` items read by `read_item`, the vector growing by bounded chunks
+pub fn read_items<R, T, F>(reader: &mut R, count: usize, mut read_item: F) -> io::Result<Vec<T>>
+where
+    R: Read,
+    F: FnMut(&mut R) -> io::Result<T>,
+{
+    let mut items = Vec::with_capacity(count.min(READ_CHUNK));
+    for _ in 0..count {
+        items.push(read_item(reader)?);
+    }
+    Ok(items)
+}
+
+/// `len` bytes, read by bounded chunks
+pub fn read_bytes<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(len.min(READ_CHUNK));
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated"));
+    }
+    Ok(bytes)
+}
+
+/// Close the current section by writing its checksum
+pub fn write_checksum<W: Write>(writer: &mut Checksummed<W>) -> io::Result<()> {
+    let checksum = writer.take_checksum();
+    write_u64(writer.get_mut(), checksum)
+}
+
+/// Close the current section, returns the (stored, computed) checksums
+pub fn read_checksum<R: Read>(reader: &mut Checksummed<R>) -> io::Result<(u64, u64)> {
+    let computed = reader.take_checksum();
+    let stored = read_u64(reader.get_mut())?;
+    Ok((stored, computed))
+}
+
+pub fn check_checksum<R: Read>(reader: &mut Checksummed<R>, section: &str) -> io::Result<()> {
+    let (stored, computed) = read_checksum(reader)?;
+    if stored != computed {
+        return Err(invalid_data(format!(
+            "corrupted {} section (checksum {:016x}, expected {:016x})",
+            section, computed, stored
+        )));
+    }
+    Ok(())
+}
+
+pub struct Header {
+    pub k: u32,
+    pub lf: u32,
+    pub w: u32,
+    pub e: u32,
+    pub min_score: u32,
+    pub shard: u32,
+    pub nb_shards: u32,
+    pub id_bytes: u32,
+    pub pos_bytes: u32,
+    pub genome_numbers: u64,
+}
+
+impl Header {
+    pub fn fingerprint_range(&self) -> u64 {
+        1u64 << self.w
+    }
+}
+
+pub fn write_u32<W: Write>(writer: &mut W, val: u32) -> io::Result<()> {
+    writer.write_all(&val.to_le_bytes())
+}
+
+pub fn write_u64<W: Write>(writer: &mut W, val: u64) -> io::Result<()> {
+    writer.write_all(&val.to_le_bytes())
+}
+
+pub fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn write_header<W: Write>(writer: &mut W, header: &Header) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    write_u32(writer, VERSION)?;
+    write_u32(writer, header.k)?;
+    write_u32(writer, header.lf)?;
+    write_u32(writer, header.w)?;
+    write_u32(writer, header.e)?;
+    write_u32(writer, header.min_score)?;
+    write_u32(writer, header.shard)?;
+    write_u32(writer, header.nb_shards)?;
+    write_u32(writer, header.id_bytes)?;
+    write_u32(writer, header.pos_bytes)?;
+    write_u64(writer, header.genome_numbers)
+}
+
+pub fn read_header<R: Read>(reader: &mut R) -> io::Result<Header> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid_data(String::from("not a rustic-onika index")));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(invalid_data(format!("unsupported index version {} (expected {})", version, VERSION)));
+    }
+    let header = Header {
+        k: read_u32(reader)?,
+        lf: read_u32(reader)?,
+        w: read_u32(reader)?,
+        e: read_u32(reader)?,
+        min_score: read_u32(reader)?,
+        shard: read_u32(reader)?,
+        nb_shards: read_u32(reader)?,
+        id_bytes: read_u32(reader)?,
+        pos_bytes: read_u32(reader)?,
+        genome_numbers: read_u64(reader)?,
+    };
+    // Fingerprints index the buckets
+    if header.w == 0 || header.w > 32 || header.k == 0 || header.k > 31 || header.lf == 0 || header.lf > 31 {
+        return Err(invalid_data(format!(
+            "invalid parameters in header (k={}, log2(S)={}, W={})",
+            header.k, header.lf, header.w
+        )));
+    }
+    width::check_widths(header.id_bytes * 8, header.pos_bytes * 8, header.lf).map_err(invalid_data)?;
+    check_shard(header.shard, header.nb_shards, header.lf).map_err(invalid_data)?;
+    Ok(header)
+}
+
+/// Shard `shard` of `nb_shards` must exist, and every shard must hold at
+/// least one of the 2^lf sketch positions
+pub fn check_shard(shard: u32, nb_shards: u32, lf: u32) -> Result<(), String> {
+    if shard >= nb_shards || nb_shards as u64 > 1u64 << lf {
+        return Err(format!(
+            "shard {} out of {} shards of a sketch of {} positions",
+            shard,
+            nb_shards,
+            1u64 << lf
+        ));
+    }
+    Ok(())
+}
+
+/// Header of an index file, to pick the id and position widths to load it with
+pub fn peek_header(path: &Path) -> io::Result<Header> {
+    read_header(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn write_bucket<W: Write, G: IndexInt, P: IndexInt>(writer: &mut W, gids: &[G], positions: &[P]) -> io::Result<()> {
+    write_u64(writer, gids.len() as u64)?;
+    for &gid in gids {
+        gid.write(writer)?;
+    }
+    for &pos in positions {
+        pos.write(writer)?;
+    }
+    Ok(())
+}
+
+pub fn read_bucket<R: Read + Remaining, G: IndexInt, P: IndexInt>(reader: &mut R) -> io::Result<(Vec<G>, Vec<P>)> {
+    let n = read_u64(reader)?;
+    let n = check_count(reader, n, (G::BYTES + P::BYTES) as u64, "postings")?;
+    let gids = read_items(reader, n, |reader| G::read(reader))?;
+    let positions = read_items(reader, n, |reader| P::read(reader))?;
+    Ok((gids, positions))
+}
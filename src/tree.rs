@@ -0,0 +1,451 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// How a tree is built from a distance matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeMethod {
+    Nj,    // neighbor joining, unrooted: the root is a node of degree 3
+    Upgma, // average linkage, rooted and ultrametric
+}
+
+impl FromStr for TreeMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TreeMethod, String> {
+        match s {
+            "nj" => Ok(TreeMethod::Nj),
+            "upgma" => Ok(TreeMethod::Upgma),
+            _ => Err(format!("unknown tree method '{}', expected nj or upgma", s)),
+        }
+    }
+}
+
+impl fmt::Display for TreeMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TreeMethod::Nj => "nj",
+            TreeMethod::Upgma => "upgma",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A node of a tree: a leaf holds the index of a row of the distance matrix
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub leaf: Option<usize>,
+    pub children: Vec<(usize, f64)>, // (node, branch length)
+    pub support: Option<u32>,        // percentage of bootstrap replicates holding the same split
+}
+
+/// Tree over the rows of a distance matrix, leaves are nodes 0 to n-1
+#[derive(Clone, Debug)]
+pub struct Tree {
+    pub nodes: Vec<Node>,
+    pub root: usize,
+}
+
+impl Tree {
+    fn with_leaves(n: usize) -> Tree {
+        Tree {
+            nodes: (0..n)
+                .map(|leaf| Node {
+                    leaf: Some(leaf),
+                    children: Vec::new(),
+                    support: None,
+                })
+                .collect(),
+            root: 0,
+        }
+    }
+
+    fn add_node(&mut self, children: Vec<(usize, f64)>) -> usize {
+        self.nodes.push(Node {
+            leaf: None,
+            children,
+            support: None,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Build a tree from a symmetric distance matrix
+    pub fn build(method: TreeMethod, distances: &[Vec<f64>]) -> Tree {
+        match method {
+            TreeMethod::Nj => neighbor_joining(distances),
+            TreeMethod::Upgma => upgma(distances),
+        }
+    }
+
+    // Leaves under each node as a bitset, taken on the side without leaf 0
+    // so that a clade and its complement, the same split of an unrooted
+    // tree, compare equal. None for the leaves and the root.
+    fn splits(&self) -> Vec<Option<Vec<u64>>> {
+        if self.nodes.is_empty() {
+            return Vec::new();
+        }
+        let nb_leaves = self.nodes.iter().filter(|node| node.leaf.is_some()).count();
+        let words = nb_leaves.div_ceil(64);
+
+        // children come after their parent, the reverse is a post-order
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![self.root];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.nodes[node].children.iter().map(|&(child, _)| child));
+        }
+        let mut below = vec![Vec::new(); self.nodes.len()];
+        for &node in order.iter().rev() {
+            let mut leaves = vec![0u64; words];
+            if let Some(leaf) = self.nodes[node].leaf {
+                leaves[leaf / 64] |= 1 << (leaf % 64);
+            }
+            for &(child, _) in &self.nodes[node].children {
+                for (word, child_word) in leaves.iter_mut().zip(&below[child]) {
+                    *word |= child_word;
+                }
+            }
+            below[node] = leaves;
+        }
+
+        below
+            .into_iter()
+            .enumerate()
+            .map(|(node, mut leaves)| {
+                if self.nodes[node].leaf.is_some() || node == self.root {
+                    return None;
+                }
+                if leaves[0] & 1 != 0 {
+                    for word in leaves.iter_mut() {
+                        *word = !*word;
+                    }
+                    if nb_leaves % 64 != 0 {
+                        leaves[words - 1] &= (1 << (nb_leaves % 64)) - 1;
+                    }
+                }
+                Some(leaves)
+            })
+            .collect()
+    }
+
+    /// Set the support of each inner node to the percentage of `replicates`,
+    /// trees over the same leaves, holding the split it defines
+    pub fn set_support(&mut self, replicates: &[Tree]) {
+        let mut counts: HashMap<Vec<u64>, u32> = HashMap::new();
+        for replicate in replicates {
+            // the two children of a rooted tree define the same split
+            let splits: HashSet<Vec<u64>> = replicate.splits().into_iter().flatten().collect();
+            for split in splits {
+                *counts.entry(split).or_insert(0) += 1;
+            }
+        }
+        for (node, split) in self.splits().into_iter().enumerate() {
+            if let Some(split) = split {
+                let count = counts.get(&split).copied().unwrap_or(0);
+                self.nodes[node].support = Some((100.0 * count as f64 / replicates.len().max(1) as f64).round() as u32);
+            }
+        }
+    }
+
+    /// Newick string, leaf i being named `names[i]`. Supports are written as
+    /// labels of the inner nodes.
+    pub fn to_newick(&self, names: &[&str]) -> String {
+        let mut newick = String::new();
+        if self.nodes.is_empty() {
+            return String::from(";");
+        }
+        // Iterative walk, deep trees would overflow the stack: Enter writes
+        // a node and schedules its children, Separator and Exit close them
+        enum Step {
+            Enter(usize, Option<f64>),
+            Separator,
+            Exit(usize, Option<f64>),
+        }
+        let mut stack = vec![Step::Enter(self.root, None)];
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::Enter(node, length) => {
+                    let children = &self.nodes[node].children;
+                    if children.is_empty() {
+                        if let Some(leaf) = self.nodes[node].leaf {
+                            newick.push_str(&newick_name(names[leaf]));
+                        }
+                        push_length(&mut newick, length);
+                        continue;
+                    }
+                    newick.push('(');
+                    stack.push(Step::Exit(node, length));
+                    for (i, &(child, child_length)) in children.iter().enumerate().rev() {
+                        stack.push(Step::Enter(child, Some(child_length)));
+                        if i > 0 {
+                            stack.push(Step::Separator);
+                        }
+                    }
+                }
+                Step::Separator => newick.push(','),
+                Step::Exit(node, length) => {
+                    newick.push(')');
+                    if let Some(support) = self.nodes[node].support {
+                        newick.push_str(&support.to_string());
+                    }
+                    push_length(&mut newick, length);
+                }
+            }
+        }
+        newick.push(';');
+        newick
+    }
+}
+
+fn push_length(newick: &mut String, length: Option<f64>) {
+    if let Some(length) = length {
+        newick.push_str(&format!(":{:.6}", length));
+    }
+}
+
+// Names holding Newick punctuation or whitespace are quoted
+fn newick_name(name: &str) -> String {
+    if name.chars().any(|c| c.is_whitespace() || "()[]':;,".contains(c)) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+/// Neighbor joining (Saitou and Nei), O(n^3). Negative branch lengths are
+/// set to 0.
+pub fn neighbor_joining(distances: &[Vec<f64>]) -> Tree {
+    let n = distances.len();
+    let mut tree = Tree::with_leaves(n);
+    if n < 3 {
+        return join_small(tree, distances);
+    }
+    let mut d = distances.to_vec();
+    // slot of the matrix -> node of the tree, for the slots still active
+    let mut active: Vec<usize> = (0..n).collect();
+    let mut node_of: Vec<usize> = (0..n).collect();
+
+    while active.len() > 2 {
+        let r = active.len() as f64;
+        let sums: Vec<f64> = active.iter().map(|&a| active.iter().map(|&b| d[a][b]).sum()).collect();
+        let mut best = (f64::INFINITY, 0, 1);
+        for x in 0..active.len() {
+            for y in (x + 1)..active.len() {
+                let q = (r - 2.0) * d[active[x]][active[y]] - sums[x] - sums[y];
+                if q < best.0 {
+                    best = (q, x, y);
+                }
+            }
+        }
+        let (_, x, y) = best;
+        let (a, b) = (active[x], active[y]);
+        let length_a = d[a][b] / 2.0 + (sums[x] - sums[y]) / (2.0 * (r - 2.0));
+        let length_b = d[a][b] - length_a;
+        let joined = tree.add_node(vec![(node_of[a], length_a.max(0.0)), (node_of[b], length_b.max(0.0))]);
+
+        // the joined node takes the slot of a
+        for &c in &active {
+            if c != a && c != b {
+                let distance = (d[a][c] + d[b][c] - d[a][b]) / 2.0;
+                d[a][c] = distance;
+                d[c][a] = distance;
+            }
+        }
+        node_of[a] = joined;
+        active.remove(y);
+    }
+
+    // The last two slots are joined by an edge, the one holding the last
+    // joined node becomes a root of degree 3
+    let (a, b) = (active[0], active[1]);
+    let (root, other) = if tree.nodes[node_of[a]].leaf.is_none() { (a, b) } else { (b, a) };
+    tree.nodes[node_of[root]].children.push((node_of[other], d[a][b].max(0.0)));
+    tree.root = node_of[root];
+    tree
+}
+
+/// UPGMA: average linkage clustering, each node sits at half the distance
+/// between the clusters it joins
+pub fn upgma(distances: &[Vec<f64>]) -> Tree {
+    let n = distances.len();
+    let mut tree = Tree::with_leaves(n);
+    if n < 3 {
+        return join_small(tree, distances);
+    }
+    let mut d = distances.to_vec();
+    let mut active: Vec<usize> = (0..n).collect();
+    let mut node_of: Vec<usize> = (0..n).collect();
+    let mut sizes = vec![1usize; n];
+    let mut heights = vec![0.0f64; n];
+
+    while active.len() > 1 {
+        let mut best = (f64::INFINITY, 0, 1);
+        for x in 0..active.len() {
+            for y in (x + 1)..active.len() {
+                if d[active[x]][active[y]] < best.0 {
+                    best = (d[active[x]][active[y]], x, y);
+                }
+            }
+        }
+        let (distance, x, y) = best;
+        let (a, b) = (active[x], active[y]);
+        let height = (distance / 2.0).max(heights[a]).max(heights[b]);
+        let joined = tree.add_node(vec![
+            (node_of[a], height - heights[a]),
+            (node_of[b], height - heights[b]),
+        ]);
+
+        for &c in &active {
+            if c != a && c != b {
+                let average = (sizes[a] as f64 * d[a][c] + sizes[b] as f64 * d[b][c]) / (sizes[a] + sizes[b]) as f64;
+                d[a][c] = average;
+                d[c][a] = average;
+            }
+        }
+        node_of[a] = joined;
+        sizes[a] += sizes[b];
+        heights[a] = height;
+        active.remove(y);
+    }
+    tree.root = node_of[active[0]];
+    tree
+}
+
+/// Weight of each of the `nb_bins` sketch positions in a bootstrap
+/// replicate: as many positions as `positions` holds are drawn from it with
+/// replacement. The same seed gives the same replicate.
+pub fn resample_positions(positions: Range<usize>, nb_bins: usize, seed: u64) -> Vec<u32> {
+    let mut weights = vec![0; nb_bins];
+    let mut state = seed;
+    for _ in positions.clone() {
+        // splitmix64
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut x = state;
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+        x ^= x >> 31;
+        let position = positions.start + ((x as u128 * positions.len() as u128) >> 64) as usize;
+        weights[position] += 1;
+    }
+    weights
+}
+
+// Trees of fewer than 3 genomes: a single leaf, or two leaves at half
+// their distance from the root
+fn join_small(mut tree: Tree, distances: &[Vec<f64>]) -> Tree {
+    if distances.len() == 2 {
+        let half = (distances[0][1] / 2.0).max(0.0);
+        tree.root = tree.add_node(vec![(0, half), (1, half)]);
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Length of the path between every two leaves
+    fn leaf_distances(tree: &Tree, n: usize) -> Vec<Vec<f64>> {
+        let mut edges = vec![Vec::new(); tree.nodes.len()];
+        for (node, content) in tree.nodes.iter().enumerate() {
+            for &(child, length) in &content.children {
+                edges[node].push((child, length));
+                edges[child].push((node, length));
+            }
+        }
+        (0..n)
+            .map(|leaf| {
+                let mut distances = vec![f64::NAN; tree.nodes.len()];
+                distances[leaf] = 0.0;
+                let mut stack = vec![leaf];
+                while let Some(node) = stack.pop() {
+                    for &(next, length) in &edges[node] {
+                        if distances[next].is_nan() {
+                            distances[next] = distances[node] + length;
+                            stack.push(next);
+                        }
+                    }
+                }
+                distances.truncate(n);
+                distances
+            })
+            .collect()
+    }
+
+    fn assert_distances(tree: &Tree, expected: &[Vec<f64>]) {
+        let distances = leaf_distances(tree, expected.len());
+        for (row, expected_row) in distances.iter().zip(expected) {
+            for (distance, expected) in row.iter().zip(expected_row) {
+                assert!((distance - expected).abs() < 1e-9, "{:?} instead of {:?}", distances, expected);
+            }
+        }
+    }
+
+    fn leaf_sets(tree: &Tree) -> Vec<Vec<u64>> {
+        tree.splits().into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn neighbor_joining_additive() {
+        // Saitou and Nei's example: ((a:2,b:3):3,c:4,(d:2,e:1):2)
+        let distances = vec![
+            vec![0.0, 5.0, 9.0, 9.0, 8.0],
+            vec![5.0, 0.0, 10.0, 10.0, 9.0],
+            vec![9.0, 10.0, 0.0, 8.0, 7.0],
+            vec![9.0, 10.0, 8.0, 0.0, 3.0],
+            vec![8.0, 9.0, 7.0, 3.0, 0.0],
+        ];
+        let tree = Tree::build(TreeMethod::Nj, &distances);
+        assert_eq!(tree.nodes[tree.root].children.len(), 3);
+        assert_distances(&tree, &distances);
+        // splits {c, d, e} and {d, e}, on the side without a
+        let mut splits = leaf_sets(&tree);
+        splits.sort();
+        assert_eq!(splits, vec![vec![0b11000], vec![0b11100]]);
+    }
+
+    #[test]
+    fn upgma_ultrametric() {
+        // ((a:1,b:1):2,c:3):2,d:5
+        let distances = vec![
+            vec![0.0, 2.0, 6.0, 10.0],
+            vec![2.0, 0.0, 6.0, 10.0],
+            vec![6.0, 6.0, 0.0, 10.0],
+            vec![10.0, 10.0, 10.0, 0.0],
+        ];
+        let tree = Tree::build(TreeMethod::Upgma, &distances);
+        assert_eq!(tree.nodes[tree.root].children.len(), 2);
+        assert_distances(&tree, &distances);
+        let mut splits = leaf_sets(&tree);
+        splits.sort();
+        // {a, b}, {a, b, c} taken as {d}, the root has no split
+        assert_eq!(splits, vec![vec![0b1000], vec![0b1100]]);
+        assert_eq!(
+            tree.to_newick(&["a", "b", "c", "d"]),
+            "(((a:1.000000,b:1.000000):2.000000,c:3.000000):2.000000,d:5.000000);"
+        );
+    }
+
+    #[test]
+    fn support_of_identical_replicates() {
+        let distances = vec![
+            vec![0.0, 5.0, 9.0, 9.0, 8.0],
+            vec![5.0, 0.0, 10.0, 10.0, 9.0],
+            vec![9.0, 10.0, 0.0, 8.0, 7.0],
+            vec![9.0, 10.0, 8.0, 0.0, 3.0],
+            vec![8.0, 9.0, 7.0, 3.0, 0.0],
+        ];
+        for method in [TreeMethod::Nj, TreeMethod::Upgma] {
+            let mut tree = Tree::build(method, &distances);
+            tree.set_support(&[tree.clone(), tree.clone(), tree.clone()]);
+            for (node, content) in tree.nodes.iter().enumerate() {
+                if content.leaf.is_some() || node == tree.root {
+                    assert_eq!(content.support, None);
+                } else {
+                    assert_eq!(content.support, Some(100));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::metadata::GenomeMetadata;
+use crate::sketch_store::SketchStore;
+use crate::storage::{self, invalid_data, Checksummed, Header};
+use crate::width::{self, IndexInt, WidthVisitor};
+
+// Only the first problems of each check are listed, the others are counted
+const MAX_REPORTED: usize = 100;
+
+pub struct Report {
+    pub issues: Vec<String>,
+    pub nb_issues: usize,
+}
+
+impl Report {
+    fn add(&mut self, location: String, message: String) {
+        if self.issues.len() < MAX_REPORTED {
+            self.issues.push(format!("{}: {}", location, message));
+        }
+        self.nb_issues += 1;
+    }
+
+    fn check_section<R: Read>(&mut self, reader: &mut Checksummed<R>, section: &str) -> io::Result<()> {
+        let (stored, computed) = storage::read_checksum(reader)?;
+        if stored != computed {
+            self.add(
+                format!("{} section", section),
+                format!("checksum {:016x}, expected {:016x}", computed, stored),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Check the checksums of an index file, then that every posting refers to an
+/// existing genome and to a sketch position of the shard, and that the stored
+/// sketches hold exactly the postings of the buckets
+pub fn verify_file(path: &Path) -> Report {
+    let mut report = Report {
+        issues: Vec::new(),
+        nb_issues: 0,
+    };
+    if let Err(e) = verify(path, &mut report) {
+        report.add(String::from("file"), format!("unreadable past this point: {}", e));
+    }
+    report
+}
+
+// The header gives the widths of the genome ids and positions of the postings
+fn verify(path: &Path, report: &mut Report) -> io::Result<()> {
+    let header = storage::peek_header(path)?;
+    width::with_widths(header.id_bytes * 8, header.pos_bytes * 8, Verify { path, report }).map_err(invalid_data)?
+}
+
+struct Verify<'a> {
+    path: &'a Path,
+    report: &'a mut Report,
+}
+
+impl WidthVisitor for Verify<'_> {
+    type Output = io::Result<()>;
+
+    fn visit<G: IndexInt, P: IndexInt>(self) -> io::Result<()> {
+        verify_widths::<G, P>(self.path, self.report)
+    }
+}
+
+fn verify_widths<G: IndexInt, P: IndexInt>(path: &Path, report: &mut Report) -> io::Result<()> {
+    // First pass: checksums and bounds of the postings
+    let mut reader = storage::open_index(path)?;
+    let header = storage::read_header(&mut reader)?;
+    report.check_section(&mut reader, "header")?;
+    // The rest of the file is read with the sizes of the header
+    if report.nb_issues > 0 {
+        return Ok(());
+    }
+    // read_header checked the shard fields
+    let f = 1u64 << header.lf;
+    let start = header.shard as u64 * f / header.nb_shards as u64;
+    let end = (header.shard as u64 + 1) * f / header.nb_shards as u64;
+
+    for fingerprint in 0..header.fingerprint_range() {
+        let (gids, positions) = storage::read_bucket::<_, G, P>(&mut reader)?;
+        for (entry, (&gid, &pos)) in gids.iter().zip(positions.iter()).enumerate() {
+            let location = format!("bucket {} entry {}", fingerprint, entry);
+            let (gid, pos) = (gid.to_usize() as u64, pos.to_usize() as u64);
+            if gid >= header.genome_numbers {
+                report.add(location.clone(), format!("genome id {} >= {} genomes", gid, header.genome_numbers));
+            }
+            if pos >= f {
+                report.add(location, format!("position {} >= sketch size {}", pos, f));
+            } else if pos < start || pos >= end {
+                report.add(location, format!("position {} outside of the shard range [{}, {})", pos, start, end));
+            }
+        }
+    }
+    report.check_section(&mut reader, "buckets")?;
+
+    let bins = (end - start) as usize;
+    let sketches = SketchStore::read(&mut reader, header.w, bins, header.genome_numbers)?;
+    report.check_section(&mut reader, "sketches")?;
+    for _ in 0..header.genome_numbers {
+        GenomeMetadata::read(&mut reader)?;
+    }
+    report.check_section(&mut reader, "metadata")?;
+    let mut trailing = [0u8; 1];
+    if reader.get_mut().read(&mut trailing)? != 0 {
+        report.add(String::from("file"), String::from("unexpected bytes after the metadata section"));
+    }
+
+    // Second pass: postings against the stored sketches
+    check_postings::<G, P>(path, &header, start as usize, &sketches, report)
+}
+
+fn check_postings<G: IndexInt, P: IndexInt>(path: &Path, header: &Header, start: usize, sketches: &SketchStore, report: &mut Report) -> io::Result<()> {
+    let mut reader = storage::open_index(path)?;
+    storage::read_header(&mut reader)?;
+    storage::read_checksum(&mut reader)?;
+
+    let fingerprint_range = header.fingerprint_range();
+    let mut postings_per_genome = vec![0usize; header.genome_numbers as usize];
+    for fingerprint in 0..fingerprint_range {
+        let (gids, positions) = storage::read_bucket::<_, G, P>(&mut reader)?;
+        for (entry, (&gid, &pos)) in gids.iter().zip(positions.iter()).enumerate() {
+            let (gid, pos) = (gid.to_usize(), pos.to_usize());
+            if gid as u64 >= header.genome_numbers || pos < start || pos - start >= sketches.get_bins() {
+                continue;
+            }
+            postings_per_genome[gid] += 1;
+            let stored = sketches.get_bin(gid, pos - start);
+            if stored != fingerprint {
+                report.add(
+                    format!("bucket {} entry {}", fingerprint, entry),
+                    format!("genome {} position {} has fingerprint {} in its stored sketch", gid, pos, stored),
+                );
+            }
+        }
+    }
+
+    // Every bin of a non empty sketch has exactly one posting
+    for (gid, &count) in postings_per_genome.iter().enumerate() {
+        let expected = (0..sketches.get_bins())
+            .filter(|&i| sketches.get_bin(gid, i) < fingerprint_range)
+            .count();
+        if count != expected {
+            report.add(
+                format!("genome {}", gid),
+                format!("{} postings for {} sketch positions", count, expected),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Index;
+    use crate::sketcher::{Params, Sketch};
+    use std::fs;
+    use std::path::PathBuf;
+
+    const PARAMS: Params = Params { k: 15, lf: 8, w: 8, e: 1000 };
+    const HEADER_BYTES: usize = 56;
+
+    // Dumped index of three genomes and the offsets of its sections
+    struct Dumped {
+        bytes: Vec<u8>,
+        buckets: usize,
+        sketches: usize,
+        metadata: usize,
+    }
+
+    fn dumped(path: &Path) -> Dumped {
+        let mut index: Index = Index::new(PARAMS).unwrap();
+        for g in 0..3u64 {
+            let bins = (0..256u64).map(|i| (i * 7 + g * (i % 3)) % 256).collect();
+            index.insert_genome(&Sketch::from_bins(bins), GenomeMetadata::new(&format!("g{}", g), &[], 1000)).unwrap();
+        }
+        index.dump(path).unwrap();
+        let postings: usize = (0..256).map(|fingerprint| index.get_bucket(fingerprint).unwrap().0.len()).sum();
+        let buckets = HEADER_BYTES + 8;
+        let sketches = buckets + 256 * 8 + postings * 6 + 8;
+        let metadata = sketches + 3 + 3 * 32 * 8 + 8;
+        Dumped {
+            bytes: fs::read(path).unwrap(),
+            buckets,
+            sketches,
+            metadata,
+        }
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("onika-verify-{}-{}.idx", name, std::process::id()))
+    }
+
+    // Report on `bytes` written as an index file
+    fn verify_bytes(name: &str, bytes: &[u8]) -> Report {
+        let path = test_path(name);
+        fs::write(&path, bytes).unwrap();
+        let report = verify_file(&path);
+        fs::remove_file(&path).unwrap();
+        report
+    }
+
+    #[test]
+    fn intact_index_passes() {
+        let path = test_path("intact");
+        let dumped = dumped(&path);
+        assert_eq!(dumped.bytes.len(), dumped.metadata + 3 * (4 + 2 + 4 + 4 + 2 + 8 * 4 + 4) + 8);
+        let report = verify_file(&path);
+        assert_eq!(report.nb_issues, 0, "{:?}", report.issues);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flipped_byte_is_found_in_each_section() {
+        let dumped = dumped(&test_path("sections"));
+        let sections = [
+            ("header", 8 + 4 + 4 * 3),
+            ("buckets", dumped.buckets + 8),
+            ("sketches", dumped.sketches + 3 + 8),
+            ("metadata", dumped.metadata + 4),
+        ];
+        for (section, offset) in sections {
+            let mut bytes = dumped.bytes.clone();
+            bytes[offset] ^= 1;
+            let report = verify_bytes(section, &bytes);
+            let expected = format!("{} section: checksum", section);
+            assert!(report.issues.iter().any(|issue| issue.starts_with(&expected)), "{}: {:?}", section, report.issues);
+        }
+    }
+
+    #[test]
+    fn truncated_file_is_reported() {
+        let dumped = dumped(&test_path("truncated"));
+        for len in [dumped.buckets + 100, dumped.sketches + 10, dumped.bytes.len() - 1] {
+            let report = verify_bytes("truncated", &dumped.bytes[..len]);
+            assert!(report.issues.iter().any(|issue| issue.starts_with("file: unreadable past this point")), "{} bytes: {:?}", len, report.issues);
+        }
+    }
+
+    #[test]
+    fn oversized_count_is_reported() {
+        let dumped = dumped(&test_path("oversized"));
+        let mut bytes = dumped.bytes.clone();
+        bytes[dumped.buckets..dumped.buckets + 8].copy_from_slice(&(u64::MAX / 4).to_le_bytes());
+        let report = verify_bytes("oversized", &bytes);
+        assert_eq!(report.nb_issues, 1);
+        assert!(report.issues[0].contains("postings exceed the"), "{:?}", report.issues);
+
+        let mut bytes = dumped.bytes.clone();
+        bytes[dumped.metadata..dumped.metadata + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let report = verify_bytes("oversized-name", &bytes);
+        assert!(report.issues.iter().any(|issue| issue.contains("exceed the")), "{:?}", report.issues);
+    }
+}
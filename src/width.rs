@@ -0,0 +1,129 @@
+use std::fmt::{Debug, Display};
+use std::io::{self, Read, Write};
+
+/// Unsigned integers used to store genome ids and sketch positions in the
+/// buckets: u16 ids for small panels, u64 ids for very large collections,
+/// u32 positions for sketches of more than 2^16 bins
+pub trait IndexInt: Copy + Ord + Debug + Display + Send + Sync + 'static {
+    const BYTES: u32;
+
+    fn max_value() -> u64;
+
+    // Callers check against max_value beforehand
+    fn from_usize(val: usize) -> Self;
+
+    fn to_usize(self) -> usize;
+
+    fn write<W: Write>(self, writer: &mut W) -> io::Result<()>;
+
+    fn read<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! index_int {
+    ($int:ty, $bytes:expr) => {
+        impl IndexInt for $int {
+            const BYTES: u32 = $bytes;
+
+            fn max_value() -> u64 {
+                <$int>::MAX as u64
+            }
+
+            fn from_usize(val: usize) -> Self {
+                debug_assert!(val as u64 <= <Self as IndexInt>::max_value());
+                val as $int
+            }
+
+            fn to_usize(self) -> usize {
+                self as usize
+            }
+
+            fn write<W: Write>(self, writer: &mut W) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+
+            fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+                let mut buf = [0u8; $bytes];
+                reader.read_exact(&mut buf)?;
+                Ok(<$int>::from_le_bytes(buf))
+            }
+        }
+    };
+}
+
+index_int!(u16, 2);
+index_int!(u32, 4);
+index_int!(u64, 8);
+
+/// Genome id and position widths, in bits, accepted for an index
+pub fn check_widths(id_bits: u32, pos_bits: u32, lf: u32) -> Result<(), String> {
+    if ![16, 32, 64].contains(&id_bits) {
+        return Err(format!("genome ids can be 16, 32 or 64 bits wide, not {}", id_bits));
+    }
+    if ![16, 32].contains(&pos_bits) {
+        return Err(format!("sketch positions can be 16 or 32 bits wide, not {}", pos_bits));
+    }
+    if lf > pos_bits {
+        return Err(format!(
+            "a sketch of 2^{} bins needs positions wider than {} bits",
+            lf, pos_bits
+        ));
+    }
+    Ok(())
+}
+
+/// Code generic over the widths of an index, for widths only known at run
+/// time. `with_widths` calls `visit` with the matching integer types.
+pub trait WidthVisitor {
+    type Output;
+
+    fn visit<G: IndexInt, P: IndexInt>(self) -> Self::Output;
+}
+
+/// Run `visitor` with the genome id and position types of `id_bits` and
+/// `pos_bits`, fails on widths no index can have
+pub fn with_widths<V: WidthVisitor>(id_bits: u32, pos_bits: u32, visitor: V) -> Result<V::Output, String> {
+    match (id_bits, pos_bits) {
+        (16, 16) => Ok(visitor.visit::<u16, u16>()),
+        (16, 32) => Ok(visitor.visit::<u16, u32>()),
+        (32, 16) => Ok(visitor.visit::<u32, u16>()),
+        (32, 32) => Ok(visitor.visit::<u32, u32>()),
+        (64, 16) => Ok(visitor.visit::<u64, u16>()),
+        (64, 32) => Ok(visitor.visit::<u64, u32>()),
+        (id_bits, pos_bits) => Err(check_widths(id_bits, pos_bits, 0).err().unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widths_must_fit_the_sketch() {
+        assert!(check_widths(16, 16, 16).is_ok());
+        assert!(check_widths(64, 32, 17).is_ok());
+        assert_eq!(check_widths(32, 16, 17).unwrap_err(), "a sketch of 2^17 bins needs positions wider than 16 bits");
+        assert!(check_widths(8, 16, 10).is_err());
+        assert!(check_widths(32, 64, 10).is_err());
+    }
+
+    struct Widths;
+
+    impl WidthVisitor for Widths {
+        type Output = (u32, u32);
+
+        fn visit<G: IndexInt, P: IndexInt>(self) -> (u32, u32) {
+            (G::BYTES * 8, P::BYTES * 8)
+        }
+    }
+
+    #[test]
+    fn visitor_gets_the_types_of_the_widths() {
+        for id_bits in [16, 32, 64] {
+            for pos_bits in [16, 32] {
+                assert_eq!(with_widths(id_bits, pos_bits, Widths), Ok((id_bits, pos_bits)));
+            }
+        }
+        assert!(with_widths(128, 16, Widths).is_err());
+        assert!(with_widths(16, 8, Widths).is_err());
+    }
+}
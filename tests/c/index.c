@@ -0,0 +1,94 @@
+/*
+ * Builds, queries, dumps and reloads an index through include/rustic_onika.h.
+ * Run by tests/ffi.rs with the path of a file to write the index to.
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "rustic_onika.h"
+
+#define CHECK(call)                                                                       \
+    do {                                                                                  \
+        onika_status status = (call);                                                     \
+        if (status != ONIKA_OK) {                                                         \
+            fprintf(stderr, "%s: status %d, %s\n", #call, (int)status, onika_last_error()); \
+            return 1;                                                                     \
+        }                                                                                 \
+    } while (0)
+
+#define EXPECT(condition)                                 \
+    do {                                                  \
+        if (!(condition)) {                               \
+            fprintf(stderr, "failed: %s\n", #condition);  \
+            return 1;                                     \
+        }                                                 \
+    } while (0)
+
+/* Reproducible random sequence of `len` bases */
+static char *random_sequence(unsigned long long seed, size_t len) {
+    char *sequence = malloc(len);
+    unsigned long long state = seed * 6364136223846793005ULL + 1442695040888963407ULL;
+    for (size_t i = 0; i < len; i++) {
+        state = state * 6364136223846793005ULL + 1442695040888963407ULL;
+        sequence[i] = "ACGT"[state >> 62];
+    }
+    return sequence;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s INDEX\n", argv[0]);
+        return 2;
+    }
+    onika_params params;
+    CHECK(onika_params_default(&params));
+    params.k = 15;
+    params.lf = 10;
+    params.w = 8;
+    params.e = 20000;
+
+    onika_index *index = NULL;
+    CHECK(onika_index_new(&params, 32, 16, &index));
+    onika_sketch *sketches[2];
+    for (int i = 0; i < 2; i++) {
+        char *sequence = random_sequence(i + 1, 20000);
+        CHECK(onika_sketch_buffer(index, NULL, sequence, 20000, &sketches[i]));
+        free(sequence);
+    }
+    size_t gid = 0;
+    CHECK(onika_index_insert(index, sketches[0], "first", &gid));
+    EXPECT(gid == 0);
+    CHECK(onika_index_insert(index, sketches[1], "second", &gid));
+    EXPECT(gid == 1);
+    CHECK(onika_index_dump(index, argv[1]));
+    onika_index_free(index);
+
+    onika_index *loaded = NULL;
+    CHECK(onika_index_load(argv[1], &loaded));
+    EXPECT(onika_index_nb_genomes(loaded) == 2);
+
+    size_t name_len = 0;
+    CHECK(onika_index_genome_name(loaded, 1, NULL, 0, &name_len));
+    char *name = malloc(name_len + 1);
+    CHECK(onika_index_genome_name(loaded, 1, name, name_len + 1, NULL));
+    EXPECT(strcmp(name, "second") == 0);
+    free(name);
+
+    onika_result *result = NULL;
+    CHECK(onika_index_query(loaded, sketches[1], 1, &result));
+    size_t len = 0;
+    const onika_hit *hits = onika_result_hits(result, &len);
+    EXPECT(len == 1 && hits[0].gid == 1 && hits[0].count == 1u << params.lf);
+    onika_result_free(result);
+
+    EXPECT(onika_index_insert(loaded, NULL, NULL, NULL) == ONIKA_INVALID_PARAMETERS);
+    EXPECT(onika_last_error() != NULL);
+
+    onika_sketch_free(sketches[0]);
+    onika_sketch_free(sketches[1]);
+    onika_index_free(loaded);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// Empty directory of its own for each test
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("onika-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Reproducible random sequence of `len` bases
+fn random_sequence(seed: u64, len: usize) -> String {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b"ACGT"[(state >> 62) as usize] as char
+        })
+        .collect()
+}
+
+// Write each (name, sequence) genome to `dir` as FASTA, and a file of files
+// listing them in order
+fn write_genomes(dir: &Path, genomes: &[(&str, &str)]) -> PathBuf {
+    let mut fof = String::new();
+    for (name, sequence) in genomes {
+        let path = dir.join(name);
+        fs::write(&path, format!(">{}\n{}\n", name, sequence)).unwrap();
+        fof.push_str(&format!("{}\n", path.display()));
+    }
+    let fof_path = dir.join("fof.txt");
+    fs::write(&fof_path, fof).unwrap();
+    fof_path
+}
+
+// Three related genomes: b holds the first half of a, c is unrelated
+fn three_genomes(dir: &Path) -> PathBuf {
+    let a = random_sequence(1, 20000);
+    let b = a[..10000].to_string();
+    let c = random_sequence(2, 20000);
+    write_genomes(dir, &[("a.fasta", &a), ("b.fasta", &b), ("c.fasta", &c)])
+}
+
+fn onika(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_rustic-onika")).args(args).output().unwrap();
+    assert!(output.status.success(), "{:?} failed: {}", args, String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+// Run a command that must fail, for its stderr
+fn onika_fails(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rustic-onika")).args(args).output().unwrap();
+    assert!(!output.status.success(), "{:?} succeeded", args);
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8(output.stdout.clone()).unwrap()
+}
+
+// Small sketches keep the tests fast
+const PARAMS: [&str; 4] = ["-S", "10", "-E", "20000"];
+
+#[test]
+fn stdout_only_holds_the_results() {
+    let dir = test_dir("stdout");
+    let fof = three_genomes(&dir);
+    let fof = fof.to_str().unwrap();
+
+    for format in ["tsv", "csv", "jsonl"] {
+        let file = dir.join(format!("dist.{}", format));
+        onika(&[&PARAMS[..], &["-I", fof, "--dist", "--format", format, "--output", file.to_str().unwrap()]].concat());
+        let piped = onika(&[&PARAMS[..], &["-I", fof, "--dist", "--format", format]].concat());
+        assert_eq!(stdout(&piped), fs::read_to_string(&file).unwrap(), "--format {}", format);
+        assert!(String::from_utf8_lossy(&piped.stderr).contains("Adding file"));
+    }
+
+    let tree = stdout(&onika(&[&PARAMS[..], &["-I", fof, "tree"]].concat()));
+    assert_eq!(tree.lines().count(), 1);
+    assert!(tree.ends_with(";\n"));
+
+    let info = stdout(&onika(&[&PARAMS[..], &["-I", fof, "info", "--json"]].concat()));
+    assert_eq!(info.lines().count(), 1);
+    assert!(info.starts_with('{') && info.contains("\"genomes\":3"));
+
+    let knn = stdout(&onika(&[&PARAMS[..], &["-I", fof, "knn", "-k", "1"]].concat()));
+    assert!(knn.starts_with("source\ttarget\t"));
+    assert!(!knn.contains("Adding file"));
+
+    let clusters = stdout(&onika(&[&PARAMS[..], &["-I", fof, "cluster"]].concat()));
+    assert!(clusters.starts_with("genome\tcluster\trepresentative\n"));
+    assert_eq!(clusters.lines().count(), 4);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn commands_read_an_index_built_out_of_core() {
+    let dir = test_dir("external");
+    let fof = three_genomes(&dir);
+    let index = dir.join("external.idx");
+    let build = [&PARAMS[..], &["-I", fof.to_str().unwrap(), "-M", "1", "-O", index.to_str().unwrap()]].concat();
+
+    let info = stdout(&onika(&[&build[..], &["info", "--json"]].concat()));
+    assert!(info.contains("\"genomes\":3"), "{}", info);
+    let tree = stdout(&onika(&[&build[..], &["tree"]].concat()));
+    assert!(tree.contains("a.fasta") && tree.contains("b.fasta") && tree.contains("c.fasta"), "{}", tree);
+    let clusters = stdout(&onika(&[&build[..], &["cluster"]].concat()));
+    assert_eq!(clusters.lines().count(), 4);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn duplicates_are_skipped_by_default() {
+    let dir = test_dir("duplicates");
+    let a = random_sequence(1, 20000);
+    let fof = write_genomes(&dir, &[("a.fasta", &a), ("copy.fasta", &a)]);
+    let a_path = dir.join("a.fasta");
+    let listed = format!("{}\n{}", fs::read_to_string(&fof).unwrap(), a_path.display());
+    fs::write(&fof, listed).unwrap();
+    let fof = fof.to_str().unwrap();
+
+    let skipped = stdout(&onika(&[&PARAMS[..], &["-I", fof, "info", "--json"]].concat()));
+    assert!(skipped.contains("\"genomes\":1,"), "{}", skipped);
+    let warned = stdout(&onika(&[&PARAMS[..], &["-I", fof, "--duplicates", "warn", "info", "--json"]].concat()));
+    assert!(warned.contains("\"genomes\":3,"), "{}", warned);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pairs_show_the_metadata_of_both_genomes() {
+    let dir = test_dir("metadata");
+    let fof = three_genomes(&dir);
+    let tagged: String = fs::read_to_string(&fof).unwrap().lines().map(|line| format!("{}\tspecies=s{}\n", line, line.len())).collect();
+    fs::write(&fof, tagged).unwrap();
+    let queries = dir.join("queries.txt");
+    fs::write(&queries, format!("{}\n", dir.join("b.fasta").display())).unwrap();
+    let fof = fof.to_str().unwrap();
+
+    let dist = stdout(&onika(&[&PARAMS[..], &["-I", fof, "--dist"]].concat()));
+    let header: Vec<&str> = dist.lines().next().unwrap().split('\t').collect();
+    let tags = header.iter().position(|&column| column == "reference_tags").unwrap();
+    let bases = header.iter().position(|&column| column == "query_bases").unwrap();
+    let rows: Vec<Vec<&str>> = dist.lines().skip(1).map(|line| line.split('\t').collect()).collect();
+    assert_eq!(rows.len(), 6);
+    for row in &rows {
+        assert_eq!(row.len(), header.len());
+        assert!(row[tags].starts_with("species=s"), "{:?}", row);
+        let expected = if row[0].ends_with("b.fasta") { "10000" } else { "20000" };
+        assert_eq!(row[bases], expected);
+    }
+
+    let hits = stdout(&onika(&[&PARAMS[..], &["-I", fof, "-Q", queries.to_str().unwrap(), "--format", "jsonl"]].concat()));
+    let best = hits.lines().next().unwrap();
+    assert!(best.contains("b.fasta\",\"reference\":"), "{}", best);
+    assert!(best.contains("\"query_bases\":10000,") && best.contains("\"query_tags\":{}"), "{}", best);
+    assert!(best.contains("\"reference_tags\":{\"species\":\"s"), "{}", best);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Values of a --format matrix output, row by row, None on the diagonal
+fn matrix_values(output: &str) -> Vec<Vec<Option<f64>>> {
+    output
+        .lines()
+        .filter(|line| !line.starts_with("##"))
+        .map(|line| line.trim_end().split('\t').skip(1).map(|value| value.parse().ok()).collect())
+        .collect()
+}
+
+#[test]
+fn dist_matrix_holds_containments_or_jaccard_distances() {
+    let dir = test_dir("matrix");
+    let fof = three_genomes(&dir);
+    let fof = fof.to_str().unwrap();
+
+    // b is the first half of a: b is contained in a, a only half in b
+    let containment = matrix_values(&stdout(&onika(&[&PARAMS[..], &["-I", fof, "--dist", "--format", "matrix"]].concat())));
+    assert_eq!(containment.len(), 3);
+    assert!(containment.iter().enumerate().all(|(i, row)| row.len() == 3 && row[i].is_none()));
+    let (a_in_b, b_in_a) = (containment[0][1].unwrap(), containment[1][0].unwrap());
+    assert!(b_in_a > 0.9, "{}", b_in_a);
+    assert!((0.35..0.65).contains(&a_in_b), "{}", a_in_b);
+    assert!(containment[0][2].unwrap() < 0.05 && containment[2][0].unwrap() < 0.05);
+
+    let jaccard = matrix_values(&stdout(&onika(&[&PARAMS[..], &["-I", fof, "--dist", "--symmetric", "--format", "matrix"]].concat())));
+    let pairs = stdout(&onika(&[&PARAMS[..], &["-I", fof, "--dist", "--symmetric"]].concat()));
+    let header: Vec<&str> = pairs.lines().next().unwrap().split('\t').collect();
+    let column = header.iter().position(|&column| column == "jaccard").unwrap();
+    let a_b: Vec<&str> = pairs.lines().nth(1).unwrap().split('\t').collect();
+    assert!(a_b[0].ends_with("a.fasta") && a_b[1].ends_with("b.fasta"));
+    let expected = 1.0 - a_b[column].parse::<f64>().unwrap();
+    assert!((jaccard[0][1].unwrap() - expected).abs() < 1e-4, "{:?} {}", jaccard, expected);
+    for (i, row) in jaccard.iter().enumerate() {
+        assert!(row.iter().enumerate().all(|(j, &value)| value == jaccard[j][i]), "{:?}", jaccard);
+    }
+
+    let mash = matrix_values(&stdout(&onika(&[&PARAMS[..], &["-I", fof, "--dist", "--mash", "--format", "matrix"]].concat())));
+    assert!(mash[0][1].unwrap() < jaccard[0][1].unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn shards_are_queried_together_or_with_a_warning() {
+    let dir = test_dir("shards");
+    let fof = three_genomes(&dir);
+    let fof = fof.to_str().unwrap();
+    let queries = dir.join("queries.txt");
+    fs::write(&queries, format!("{}\n{}\n", dir.join("b.fasta").display(), dir.join("a.fasta").display())).unwrap();
+    let queries = queries.to_str().unwrap();
+    let shard_files: Vec<String> = (0..2).map(|j| dir.join(format!("shard{}.idx", j)).display().to_string()).collect();
+    for (j, shard_file) in shard_files.iter().enumerate() {
+        let shard = j.to_string();
+        onika(&[&PARAMS[..], &["-I", fof, "--shard", &shard, "--shards", "2", "-O", shard_file]].concat());
+    }
+
+    let whole = stdout(&onika(&[&PARAMS[..], &["-I", fof, "-Q", queries]].concat()));
+    let together = stdout(&onika(&["-L", &shard_files[1], "-L", &shard_files[0], "-Q", queries]));
+    assert_eq!(together, whole);
+
+    let alone = onika(&["-L", &shard_files[0], "-Q", queries]);
+    assert!(String::from_utf8_lossy(&alone.stderr).contains("Warning: only shard 0 of 2 is queried"));
+    assert_ne!(stdout(&alone), whole);
+    for command in [&["--dist"][..], &["tree"], &["cluster"], &["knn"]] {
+        let error = onika_fails(&[&["-L", &shard_files[1]][..], command].concat());
+        assert!(error.contains("not on shard 1 of 2 alone"), "{}", error);
+    }
+    let error = onika_fails(&["-L", &shard_files[1], "-Q", queries, "-L", &shard_files[1]]);
+    assert!(error.contains("missing or duplicated"), "{}", error);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bad_inputs_and_queries_are_skipped() {
+    let dir = test_dir("inputs");
+    let fof = three_genomes(&dir);
+    let fof = fof.to_str().unwrap();
+    fs::write(dir.join("empty.fasta"), ">a\n").unwrap();
+    fs::write(dir.join("short.fasta"), ">a\nACGTACGT\n").unwrap();
+    // A directory is listed as well, it cannot be read as a genome
+    let bad = [dir.join("missing.fasta"), dir.clone(), dir.join("empty.fasta"), dir.join("short.fasta")];
+    let listed = |good: &str| bad.iter().map(|path| format!("{}\n", path.display())).collect::<String>() + good;
+    let inputs = dir.join("inputs.txt");
+    fs::write(&inputs, listed(&fs::read_to_string(fof).unwrap())).unwrap();
+    let inputs = inputs.to_str().unwrap();
+
+    let report = dir.join("skipped.tsv");
+    let build = onika(&[&PARAMS[..], &["-I", inputs, "--skip-errors", report.to_str().unwrap(), "info", "--json"]].concat());
+    assert!(stdout(&build).contains("\"genomes\":3"), "{}", stdout(&build));
+    let rows: Vec<Vec<String>> = fs::read_to_string(&report)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|row| row.split('\t').take(3).map(String::from).collect())
+        .collect();
+    let expected: Vec<Vec<String>> = bad
+        .iter()
+        .zip(["missing", "unreadable", "empty", "too_short"])
+        .enumerate()
+        .map(|(i, (path, kind))| vec![(i + 1).to_string(), path.display().to_string(), kind.to_string()])
+        .collect();
+    assert_eq!(rows, expected);
+    let error = onika_fails(&[&PARAMS[..], &["-I", inputs, "--strict"]].concat());
+    assert!(error.contains("is missing"), "{}", error);
+
+    let queries = dir.join("queries.txt");
+    fs::write(&queries, listed(&format!("{}\n", dir.join("a.fasta").display()))).unwrap();
+    let good = dir.join("good.txt");
+    fs::write(&good, format!("{}\n", dir.join("a.fasta").display())).unwrap();
+    let queried = onika(&[&PARAMS[..], &["-I", fof, "-Q", queries.to_str().unwrap()]].concat());
+    let stderr = String::from_utf8_lossy(&queried.stderr);
+    assert!(stderr.contains(&format!("Skipping query, line 1: '{}' is missing", bad[0].display())), "{}", stderr);
+    assert!(stderr.contains("Skipping query, line 4:"), "{}", stderr);
+    assert!(stderr.contains("4 inputs skipped: 1 missing, 1 unreadable, 1 empty, 1 too_short"), "{}", stderr);
+    let only_good = onika(&[&PARAMS[..], &["-I", fof, "-Q", good.to_str().unwrap()]].concat());
+    assert_eq!(stdout(&queried), stdout(&only_good));
+    assert!(!stdout(&queried).is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Pairs of a --dist --sparse edge list, as query and reference file names
+fn sparse_pairs(output: &str) -> Vec<(String, String)> {
+    let name = |path: &str| Path::new(path).file_name().unwrap().to_string_lossy().into_owned();
+    output
+        .lines()
+        .skip(1)
+        .map(|row| {
+            let fields: Vec<&str> = row.split('\t').collect();
+            (name(fields[0]), name(fields[1]))
+        })
+        .collect()
+}
+
+#[test]
+fn sparse_pairs_pass_the_default_or_given_pvalue() {
+    let dir = test_dir("pvalue");
+    let fof = three_genomes(&dir);
+    // Tiny sketches of short k-mers, so that the unrelated genomes share a
+    // position with a p-value between 0.001 and 0.01
+    let sparse = ["-K", "9", "-S", "4", "-E", "20000", "-I", fof.to_str().unwrap(), "--dist", "--sparse"];
+    let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+
+    let default = sparse_pairs(&stdout(&onika(&sparse)));
+    assert_eq!(default, [pair("a.fasta", "b.fasta")]);
+    let loose = sparse_pairs(&stdout(&onika(&[&sparse[..], &["--max-pvalue", "0.01"]].concat())));
+    assert_eq!(loose, [pair("a.fasta", "b.fasta"), pair("a.fasta", "c.fasta"), pair("b.fasta", "c.fasta")]);
+    let strict = sparse_pairs(&stdout(&onika(&[&sparse[..], &["--max-pvalue", "1e-30"]].concat())));
+    assert!(strict.is_empty(), "{:?}", strict);
+    // --min-ani alone replaces the default p-value threshold
+    let by_ani = sparse_pairs(&stdout(&onika(&[&sparse[..], &["--min-ani", "0"]].concat())));
+    assert_eq!(by_ani, loose);
+    fs::remove_dir_all(&dir).unwrap();
+}
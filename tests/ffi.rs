@@ -0,0 +1,34 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// Directory of the test executable, where cargo also puts the cdylib
+fn deps_dir() -> PathBuf {
+    std::env::current_exe().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn c_program_links_against_the_header() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let dir = std::env::temp_dir().join(format!("onika-ffi-c-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("index");
+    let deps = deps_dir();
+
+    let compiled = Command::new("cc")
+        .arg(root.join("tests/c/index.c"))
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg("-L")
+        .arg(&deps)
+        .arg(format!("-Wl,-rpath,{}", deps.display()))
+        .args(["-lrustic_onika", "-Wall", "-Werror", "-o"])
+        .arg(&program)
+        .output()
+        .unwrap();
+    assert!(compiled.status.success(), "cc failed: {}", String::from_utf8_lossy(&compiled.stderr));
+
+    let run = Command::new(&program).arg(dir.join("index.idx")).output().unwrap();
+    assert!(run.status.success(), "{}", String::from_utf8_lossy(&run.stderr));
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "ok\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}